@@ -1,11 +1,36 @@
 use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use anchor_lang::solana_program::{ed25519_program, sysvar::instructions as instructions_sysvar};
+use sha3::{Digest, Sha3_256};
 use anchor_spl::{
-    token::{self, Mint, Token, TokenAccount},
-    associated_token::AssociatedToken,
+    token::{self, Token},
+    token_2022::{
+        spl_token_2022::{
+            self,
+            extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+        },
+        Token2022,
+    },
+    token_2022_extensions::transfer_fee,
+    token_interface::{self, Mint, TokenAccount, TokenInterface},
+    associated_token::{self, get_associated_token_address, AssociatedToken},
+    metadata::{
+        mpl_token_metadata::instructions::{
+            FreezeDelegatedAccountCpiBuilder, ThawDelegatedAccountCpiBuilder, TransferV1CpiBuilder,
+        },
+        Metadata as TokenMetadata, MetadataAccount,
+    },
 };
+use mpl_bubblegum::{instructions::TransferCpiBuilder as BubblegumTransferCpiBuilder, utils::get_asset_id};
+use mpl_core::{accounts::BaseAssetV1, instructions::TransferV1CpiBuilder as CoreTransferV1CpiBuilder, types::UpdateAuthority};
 
 declare_id!("8tsvXCJwKZhNwe3U2MVWdP1n4EkgDVAq4sQCM4Ry2XqS");
 
+/// SPL Noop, Bubblegum's log wrapper
+pub const SPL_NOOP_ID: Pubkey = pubkey!("noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV");
+/// SPL Account Compression, which owns Bubblegum's Merkle trees
+pub const SPL_ACCOUNT_COMPRESSION_ID: Pubkey = pubkey!("cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK");
+
 
 #[error_code]
 pub enum CustomError {
@@ -33,6 +58,102 @@ pub enum CustomError {
     UnknownVoteType,
     #[msg("Already voted.")]
     AlreadyVoted,
+    #[msg("No admin transfer is pending.")]
+    NoPendingAdmin,
+    #[msg("Signer is not the pending admin.")]
+    NotPendingAdmin,
+    #[msg("Resolution threshold must be between 1 and the number of moderators.")]
+    InvalidResolutionThreshold,
+    #[msg("This action is currently paused.")]
+    Paused,
+    #[msg("Unknown pause flags.")]
+    InvalidPauseFlags,
+    #[msg("Deadlines must be in the future and in fee, escrow, resolution order.")]
+    InvalidDeadlines,
+    #[msg("Challenge deadline has passed.")]
+    DeadlinePassed,
+    #[msg("Challenge deadline has not passed yet.")]
+    DeadlineNotReached,
+    #[msg("Fees have already been collected.")]
+    FeesAlreadyCollected,
+    #[msg("No fee to refund.")]
+    NoFeeToRefund,
+    #[msg("NFT mint does not match the one declared on the challenge.")]
+    InvalidNftMint,
+    #[msg("NFT mint must have 0 decimals.")]
+    InvalidNftDecimals,
+    #[msg("NFT mint must have a supply of 1.")]
+    InvalidNftSupply,
+    #[msg("Source token account does not hold the declared NFT mint.")]
+    SourceMintMismatch,
+    #[msg("Source token account is not owned by the depositor.")]
+    SourceOwnerMismatch,
+    #[msg("Source token account does not hold the NFT.")]
+    SourceAccountEmpty,
+    #[msg("Challenge still holds unsettled NFTs or fees.")]
+    ChallengeNotSettled,
+    #[msg("Each side must stake between 1 and 5 distinct NFTs.")]
+    InvalidBundle,
+    #[msg("Escrow account is not the escrow PDA for this challenge and mint.")]
+    InvalidEscrowAccount,
+    #[msg("Remaining accounts do not match what this instruction expects.")]
+    InvalidRemainingAccounts,
+    #[msg("Stake type does not match this instruction.")]
+    InvalidStakeType,
+    #[msg("Token and SOL stakes must be greater than zero.")]
+    InvalidStakeAmount,
+    #[msg("Token mint does not match the one declared on the challenge.")]
+    InvalidStakeMint,
+    #[msg("Mints with a transfer hook cannot be escrowed.")]
+    TransferHookNotSupported,
+    #[msg("Mints with a permanent delegate cannot be escrowed.")]
+    PermanentDelegateNotSupported,
+    #[msg("Non-transferable mints cannot be escrowed.")]
+    NonTransferableMint,
+    #[msg("Programmable NFTs must be moved with the pNFT instructions.")]
+    ProgrammableNftTransfer,
+    #[msg("Compressed NFT does not match the one declared on the challenge.")]
+    InvalidCnftAsset,
+    #[msg("Core asset does not match the one declared on the challenge.")]
+    InvalidCoreAsset,
+    #[msg("Metadata account does not belong to the NFT mint.")]
+    InvalidMetadataAccount,
+    #[msg("NFT has no verified Metaplex collection.")]
+    CollectionNotVerified,
+    #[msg("Collection is not on the allowlist.")]
+    CollectionNotAllowed,
+    #[msg("Too many NFTs from this collection in one stake.")]
+    CollectionLimitExceeded,
+    #[msg("Stakes do not satisfy the challenge's matching rule.")]
+    MatchRuleNotMet,
+    #[msg("Expected an Ed25519 signature by an API signer before this instruction.")]
+    InvalidApiSignature,
+    #[msg("An API authorization and its nonce account are required.")]
+    MissingApiAuthorization,
+    #[msg("API authorization has expired.")]
+    ApiAuthorizationExpired,
+    #[msg("API authorization nonce has already been used.")]
+    NonceAlreadyUsed,
+    #[msg("Nonce account does not match the API signer and nonce.")]
+    InvalidNonceAccount,
+    #[msg("Join constraints allow at most 10 players.")]
+    InvalidJoinConstraints,
+    #[msg("Signer does not meet the open challenge's join constraints.")]
+    NotEligibleToJoin,
+    #[msg("Service fee is above the open challenge's maximum.")]
+    FeeAboveMaximum,
+    #[msg("No matching stake change has been proposed.")]
+    NoStakeChange,
+    #[msg("Programmable, locked, compressed and Core deposits can't be returned by a stake change.")]
+    UnreturnableDeposit,
+    #[msg("Config already has the current layout.")]
+    ConfigUpToDate,
+    #[msg("Compressed NFTs can't be staked while the collection allowlist is enforced.")]
+    CnftNotAllowlistable,
+    #[msg("API nonce can't be closed before its authorization expires.")]
+    NonceNotExpired,
+    #[msg("NFT mints with a transfer fee cannot be escrowed.")]
+    TransferFeeNft,
 }
 
 #[program]
@@ -46,11 +167,58 @@ pub mod trinity_solana {
     pub fn initialize_config(ctx: Context<InitializeConfig>, params: InitConfigParams) -> Result<()> {
         let config = &mut ctx.accounts.config;
         config.admin = ctx.accounts.admin.key();
+        config.pending_admin = None;
         config.bump = ctx.bumps.config;
         config.chain_id = params.chain_id;
         config.treasury = params.treasury;
         config.api_signers = vec![];
         config.moderator_signers = vec![];
+        config.resolution_threshold = Config::DEFAULT_RESOLUTION_THRESHOLD;
+        config.paused = 0;
+        config.refund_fees_on_draw = false;
+        config.enforce_collection_allowlist = false;
+        config.version = Config::VERSION;
+        Ok(())
+    }
+
+    /**
+     * Bring a config written by an older program version to the current layout.
+     * The account is grown to the current size, fields added since read as
+     * zero and get their defaults here.
+     * Only the admin can call this function.
+     */
+    pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+        let config_info = ctx.accounts.config.to_account_info();
+        let space = 8 + Config::MAX_SIZE;
+        if config_info.data_len() < space {
+            let shortfall = Rent::get()?
+                .minimum_balance(space)
+                .saturating_sub(config_info.lamports());
+            if shortfall > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.admin.to_account_info(),
+                            to: config_info.clone(),
+                        },
+                    ),
+                    shortfall,
+                )?;
+            }
+            config_info.realloc(space, true)?;
+        }
+
+        let mut config = Config::try_deserialize(&mut &config_info.try_borrow_data()?[..])?;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        require!(config.version < Config::VERSION, CustomError::ConfigUpToDate);
+        if config.version == 0 {
+            // Version 0 had a fixed threshold of 3 moderator votes
+            config.resolution_threshold = Config::DEFAULT_RESOLUTION_THRESHOLD;
+        }
+        config.version = Config::VERSION;
+        config.try_serialize(&mut &mut config_info.try_borrow_mut_data()?[..])?;
+
         Ok(())
     }
 
@@ -107,6 +275,27 @@ pub mod trinity_solana {
         let config = &mut ctx.accounts.config;
         require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
         config.moderator_signers.retain(|s| s != &signer);
+        // Lower the threshold first if removing this moderator would make it unreachable
+        require!(
+            config.resolution_threshold as usize <= config.moderator_signers.len(),
+            CustomError::InvalidResolutionThreshold
+        );
+        Ok(())
+    }
+
+    /**
+     * Set the number of moderator votes needed to resolve a challenge.
+     * Must be reachable with the currently registered moderators.
+     * Only the admin can call this function.
+     */
+    pub fn set_resolution_threshold(ctx: Context<UpdateConfig>, threshold: u8) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        require!(
+            threshold > 0 && threshold as usize <= config.moderator_signers.len(),
+            CustomError::InvalidResolutionThreshold
+        );
+        config.resolution_threshold = threshold;
         Ok(())
     }
 
@@ -121,492 +310,4592 @@ pub mod trinity_solana {
         Ok(())
     }
 
+    /**
+     * Nominate a new admin.
+     * The nominee only takes over once they call `accept_admin`.
+     * Only the admin can call this function.
+     */
+    pub fn propose_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        config.pending_admin = Some(new_admin);
+        Ok(())
+    }
+
+    /**
+     * Accept a pending admin nomination.
+     * Only the nominated admin can call this function.
+     */
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let pending_admin = config.pending_admin.ok_or(CustomError::NoPendingAdmin)?;
+        require!(
+            ctx.accounts.new_admin.key() == pending_admin,
+            CustomError::NotPendingAdmin
+        );
+        config.admin = pending_admin;
+        config.pending_admin = None;
+        Ok(())
+    }
+
+    /**
+     * Withdraw a pending admin nomination.
+     * Only the admin can call this function.
+     */
+    pub fn cancel_admin_transfer(ctx: Context<UpdateConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        require!(config.pending_admin.is_some(), CustomError::NoPendingAdmin);
+        config.pending_admin = None;
+        Ok(())
+    }
+
+    /**
+     * Pause one or more instruction classes (see `Config::PAUSE_*`).
+     * Only the admin can call this function.
+     */
+    pub fn pause(ctx: Context<UpdateConfig>, flags: u8) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        require!(flags & !Config::PAUSE_ALL == 0, CustomError::InvalidPauseFlags);
+        config.paused |= flags;
+        Ok(())
+    }
+
+    /**
+     * Unpause one or more instruction classes (see `Config::PAUSE_*`).
+     * Only the admin can call this function.
+     */
+    pub fn unpause(ctx: Context<UpdateConfig>, flags: u8) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        require!(flags & !Config::PAUSE_ALL == 0, CustomError::InvalidPauseFlags);
+        config.paused &= !flags;
+        Ok(())
+    }
+
+    /**
+     * Choose whether fees are refunded (true) or kept (false) when a challenge is drawn.
+     * Only the admin can call this function.
+     */
+    pub fn set_draw_fee_policy(ctx: Context<UpdateConfig>, refund_fees_on_draw: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        config.refund_fees_on_draw = refund_fees_on_draw;
+        Ok(())
+    }
+
+    /**
+     * Require staked NFTs to belong to an allowlisted collection (true) or accept any NFT (false).
+     * Only the admin can call this function.
+     */
+    pub fn set_collection_allowlist_enforced(ctx: Context<UpdateConfig>, enforced: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(ctx.accounts.admin.key() == config.admin, CustomError::Unauthorized);
+        config.enforce_collection_allowlist = enforced;
+        Ok(())
+    }
+
+    /**
+     * Add a verified Metaplex collection (or Core collection) to the allowlist.
+     * `max_nfts_per_stake` caps how many of its NFTs one side can stake, 0 for no cap.
+     * Only the admin can call this function.
+     */
+    pub fn add_allowed_collection(
+        ctx: Context<AddAllowedCollection>,
+        collection: Pubkey,
+        max_nfts_per_stake: u8,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.admin.key() == ctx.accounts.config.admin,
+            CustomError::Unauthorized
+        );
+        let allowed_collection = &mut ctx.accounts.allowed_collection;
+        allowed_collection.collection = collection;
+        allowed_collection.max_nfts_per_stake = max_nfts_per_stake;
+        allowed_collection.bump = ctx.bumps.allowed_collection;
+        Ok(())
+    }
+
+    /**
+     * Update the parameters of an allowlisted collection.
+     * Only the admin can call this function.
+     */
+    pub fn set_collection_params(
+        ctx: Context<UpdateAllowedCollection>,
+        max_nfts_per_stake: u8,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.admin.key() == ctx.accounts.config.admin,
+            CustomError::Unauthorized
+        );
+        ctx.accounts.allowed_collection.max_nfts_per_stake = max_nfts_per_stake;
+        Ok(())
+    }
+
+    /**
+     * Remove a collection from the allowlist.
+     * Only the admin can call this function.
+     */
+    pub fn remove_allowed_collection(ctx: Context<RemoveAllowedCollection>) -> Result<()> {
+        require!(
+            ctx.accounts.admin.key() == ctx.accounts.config.admin,
+            CustomError::Unauthorized
+        );
+        Ok(())
+    }
+
+    /**
+     * Create a challenge between p1 and p2.
+     * When the collection allowlist is enforced or the stakes must share a
+     * collection, pass the metadata account of every NFT in p1's bundle, then
+     * p2's, as remaining accounts, each followed by its allowlist entry while
+     * the allowlist is enforced. A Core stake passes its asset account in
+     * place of the metadata, compressed NFTs can't be staked then and never
+     * meet a same collection rule.
+     * A value tier rule needs the API's price attestation as an Ed25519
+     * program instruction right before this one, with its `authorization`
+     * and the matching `api_nonce` account.
+     */
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize_challenge(
         ctx: Context<InitializeChallenge>,
         challenge_id: u64,
         p1_fee: u64,
         p2_fee: u64,
-        nft1_mint: Pubkey,
-        nft2_mint: Pubkey,
+        p1_stake: StakeTerms,
+        p2_stake: StakeTerms,
+        deadlines: ChallengeDeadlines,
+        match_rule: MatchRule,
+        authorization: Option<ApiAuthorization>,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        require!(
+            config.api_signers.contains(&ctx.accounts.api_signer.key()),
+            CustomError::UnauthorizedApiSigner
+        );
+        let params = ChallengeParams {
+            challenge_id,
+            p1_fee,
+            p2_fee,
+            p1_stake,
+            p2_stake,
+            deadlines,
+            match_rule,
+        };
+        if let MatchRule::ValueTier { p1_value, p2_value, p1_tier, .. } = params.match_rule {
+            let (Some(authorization), Some(api_nonce)) = (authorization, &ctx.accounts.api_nonce) else {
+                return err!(CustomError::MissingApiAuthorization);
+            };
+            let message = price_attestation_message(
+                &config.chain_id,
+                &ctx.accounts.challenge.key(),
+                &params.p1_stake,
+                &params.p2_stake,
+                &params.match_rule,
+                &authorization,
+            )?;
+            let signer = verify_api_signature(&ctx.accounts.sysvar_instructions, config, &message)?;
+            consume_api_nonce(
+                api_nonce,
+                &ctx.accounts.p1,
+                &ctx.accounts.system_program,
+                &signer,
+                &authorization,
+            )?;
+            msg!(
+                "Value tier {} attested: p1 {} p2 {}",
+                p1_tier,
+                p1_value,
+                p2_value
+            );
+        }
+
+        create_challenge(
+            &mut ctx.accounts.challenge,
+            ctx.bumps.challenge,
+            config,
+            ctx.accounts.p1.key(),
+            ctx.accounts.p2.key(),
+            params,
+            ctx.remaining_accounts,
+        )
+    }
+
+    /**
+     * Create a challenge from a challenge attestation the API signed off-chain,
+     * so the API doesn't have to co-sign the transaction.
+     * The instruction right before this one must be an Ed25519 program
+     * verification of `challenge_attestation_digest` by an API signer, and
+     * `api_nonce` the nonce account for that signer and `authorization.nonce`.
+     * Remaining accounts are the same as for `initialize_challenge`.
+     */
+    pub fn initialize_attested_challenge(
+        ctx: Context<InitializeAttestedChallenge>,
+        params: ChallengeParams,
+        authorization: ApiAuthorization,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let digest = challenge_attestation_digest(
+            &config.chain_id,
+            &ctx.accounts.p1.key(),
+            &ctx.accounts.p2.key(),
+            &params,
+            &authorization,
+        )?;
+        let signer = verify_api_signature(&ctx.accounts.sysvar_instructions, config, &digest)?;
+        consume_api_nonce(
+            &ctx.accounts.api_nonce,
+            &ctx.accounts.p1,
+            &ctx.accounts.system_program,
+            &signer,
+            &authorization,
+        )?;
+
+        create_challenge(
+            &mut ctx.accounts.challenge,
+            ctx.bumps.challenge,
+            config,
+            ctx.accounts.p1.key(),
+            ctx.accounts.p2.key(),
+            params,
+            ctx.remaining_accounts,
+        )
+    }
+
+    /**
+     * Close a used API nonce once its authorization has expired, returning
+     * the rent to whoever paid it. Anyone can call this function, an expired
+     * authorization is rejected before its nonce is checked so it can't be replayed.
+     */
+    pub fn close_used_nonce(ctx: Context<CloseUsedNonce>) -> Result<()> {
+        require!(
+            Clock::get()?.unix_timestamp > ctx.accounts.api_nonce.expires_at,
+            CustomError::NonceNotExpired
+        );
+        Ok(())
+    }
+
+    /**
+     * Create an open challenge, which any player meeting `constraints` can join.
+     * p2 and their stake stay unset until `join_challenge`.
+     * Remaining accounts are p1's NFT metadata or Core asset accounts as for
+     * `initialize_challenge`. Under a same collection rule p1's collection
+     * becomes the joiner's required collection. Value tier rules can't be
+     * attested before the joiner's stake is known and are rejected.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_open_challenge(
+        ctx: Context<InitializeOpenChallenge>,
+        challenge_id: u64,
+        p1_fee: u64,
+        p1_stake: StakeTerms,
+        deadlines: ChallengeDeadlines,
+        match_rule: MatchRule,
+        constraints: JoinConstraints,
     ) -> Result<()> {
         let config = &ctx.accounts.config;
         require!(
             config.api_signers.contains(&ctx.accounts.api_signer.key()),
             CustomError::UnauthorizedApiSigner
         );
+        check_new_challenge(config, &deadlines)?;
+        StakeTerms::validate_stakes(&[&p1_stake])?;
+        let mut constraints = constraints;
+        require!(
+            constraints.allowlist.len() <= JoinConstraints::MAX_ALLOWLIST,
+            CustomError::InvalidJoinConstraints
+        );
+        match match_rule {
+            MatchRule::Any => {
+                if config.enforce_collection_allowlist {
+                    bundle_collections(&[&p1_stake], ctx.remaining_accounts, true)?;
+                }
+            }
+            MatchRule::SameCollection => {
+                require!(p1_stake.has_collection(), CustomError::MatchRuleNotMet);
+                let collections = bundle_collections(
+                    &[&p1_stake],
+                    ctx.remaining_accounts,
+                    config.enforce_collection_allowlist,
+                )?;
+                let collection = collections[0];
+                require!(
+                    collections.iter().all(|key| *key == collection)
+                        && constraints.required_collection.unwrap_or(collection) == collection,
+                    CustomError::MatchRuleNotMet
+                );
+                constraints.required_collection = Some(collection);
+            }
+            MatchRule::ValueTier { .. } => return err!(CustomError::MatchRuleNotMet),
+        }
 
         let challenge = &mut ctx.accounts.challenge;
         challenge.p1 = ctx.accounts.p1.key();
-        challenge.p2 = ctx.accounts.p2.key();
+        challenge.p2 = Pubkey::default();
         challenge.challenge_id = challenge_id;
         challenge.p1_fee = p1_fee;
-        challenge.p2_fee = p2_fee;
+        challenge.p2_fee = 0;
         challenge.p1_paid = p1_fee == 0; // Mark as paid if fee is 0
-        challenge.p2_paid = p2_fee == 0; // Mark as paid if fee is 0
-        challenge.nft1_mint = nft1_mint;
-        challenge.nft2_mint = nft2_mint;
+        challenge.p2_paid = false;
+        challenge.p1_stake = p1_stake.into();
+        challenge.p2_stake = Stake::Nft { nfts: vec![] };
         challenge.bump = ctx.bumps.challenge;
-        challenge.status = if challenge.p1_paid && challenge.p2_paid {
-            ChallengeStatus::PendingEscrow
-        } else {
-            ChallengeStatus::PendingFee
-        };
-        challenge.nft1_status = NFTStatus::NotDeposited;
-        challenge.nft2_status = NFTStatus::NotDeposited;
+        challenge.rent_payer = challenge.p1;
+        challenge.status = ChallengeStatus::Open;
         challenge.winner = None;
+        challenge.resolution_threshold = config.resolution_threshold;
+        challenge.refund_fees_on_draw = config.refund_fees_on_draw;
+        challenge.deadlines = deadlines;
+        challenge.match_rule = match_rule;
+        challenge.join_constraints = Some(constraints);
+        challenge.proposed_change = None;
 
         Ok(())
     }
 
-    pub fn pay_service_fee(ctx: Context<PayServiceFee>) -> Result<()> {
+    /**
+     * Take the p2 slot of an open challenge.
+     * The first signer meeting the challenge's join constraints wins the slot,
+     * then the challenge continues like an accepted invitation. The API
+     * co-signs and sets the joiner's fee, which can't exceed the challenge's
+     * maximum. Pass the metadata account of every NFT in `p2_stake`, or its
+     * Core asset account, each followed by its allowlist entry while the
+     * allowlist is enforced, when a collection is required.
+     */
+    pub fn join_challenge(
+        ctx: Context<JoinChallenge>,
+        p2_stake: StakeTerms,
+        p2_fee: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        require!(!config.is_paused(Config::PAUSE_CREATION), CustomError::Paused);
+        require!(
+            config.api_signers.contains(&ctx.accounts.api_signer.key()),
+            CustomError::UnauthorizedApiSigner
+        );
+
         let challenge = &mut ctx.accounts.challenge;
-        let payer = ctx.accounts.payer.key();
-
-        if payer == challenge.p1 {
-            require!(!challenge.p1_paid, CustomError::FeeAlreadyPaid);
-            // If the fee is 0, we don't need to transfer anything and it should be marked as already paid
-            if challenge.p1_fee > 0 {
-                let ix = anchor_lang::solana_program::system_instruction::transfer(
-                    ctx.accounts.payer.key,
-                    ctx.accounts.treasury.key,
-                    challenge.p1_fee,
-                );
-                anchor_lang::solana_program::program::invoke(
-                    &ix,
-                    &[
-                        ctx.accounts.payer.to_account_info(),
-                        ctx.accounts.treasury.to_account_info(),
-                    ],
-                )?;
-            }
-            challenge.p1_paid = true;
-        } else if payer == challenge.p2 {
-            require!(!challenge.p2_paid, CustomError::FeeAlreadyPaid);
-            // If the fee is 0, we don't need to transfer anything and it should be marked as already paid
-            if challenge.p2_fee > 0 {
-                let ix = anchor_lang::solana_program::system_instruction::transfer(
-                    ctx.accounts.payer.key,
-                    ctx.accounts.treasury.key,
-                    challenge.p2_fee,
+        require!(
+            challenge.status == ChallengeStatus::Open,
+            CustomError::InvalidChallengeState
+        );
+        require!(
+            Clock::get()?.unix_timestamp <= challenge.deadlines.fee,
+            CustomError::DeadlinePassed
+        );
+        let joiner = ctx.accounts.p2.key();
+        let constraints = challenge
+            .join_constraints
+            .clone()
+            .ok_or(CustomError::InvalidChallengeState)?;
+        require!(
+            joiner != challenge.p1
+                && (constraints.allowlist.is_empty() || constraints.allowlist.contains(&joiner)),
+            CustomError::NotEligibleToJoin
+        );
+        if let Some(max_fee) = constraints.max_fee {
+            require!(p2_fee <= max_fee, CustomError::FeeAboveMaximum);
+        }
+        StakeTerms::validate_stakes(&[&challenge.p1_stake.terms(), &p2_stake])?;
+        if config.enforce_collection_allowlist || constraints.required_collection.is_some() {
+            let collections = bundle_collections(
+                &[&p2_stake],
+                ctx.remaining_accounts,
+                config.enforce_collection_allowlist,
+            )?;
+            if let Some(required) = constraints.required_collection {
+                require!(
+                    p2_stake.has_collection()
+                        && collections.iter().all(|key| *key == required),
+                    CustomError::NotEligibleToJoin
                 );
-                anchor_lang::solana_program::program::invoke(
-                    &ix,
-                    &[
-                        ctx.accounts.payer.to_account_info(),
-                        ctx.accounts.treasury.to_account_info(),
-                    ],
-                )?;
             }
-            challenge.p2_paid = true;
-        } else {
-            return err!(CustomError::InvalidPayer);
         }
 
-        if challenge.p1_paid && challenge.p2_paid {
-            challenge.status = ChallengeStatus::PendingEscrow;
-        }
+        challenge.p2 = joiner;
+        challenge.p2_fee = p2_fee;
+        challenge.p2_paid = p2_fee == 0; // Mark as paid if fee is 0
+        challenge.p2_stake = p2_stake.into();
+        challenge.status = ChallengeStatus::PendingFee;
+        challenge.refresh_status();
 
         Ok(())
     }
 
-    pub fn deposit_nft(ctx: Context<DepositNft>) -> Result<()> {
-        let challenge = &mut ctx.accounts.challenge;
-        let depositor = ctx.accounts.depositor.key();
-    
-        if depositor == challenge.p1 {
-            require!(
-                challenge.nft1_status == NFTStatus::NotDeposited,
-                CustomError::AlreadyDeposited
-            );
-            challenge.nft1_status = NFTStatus::Deposited;
-        } else if depositor == challenge.p2 {
-            require!(
-                challenge.nft2_status == NFTStatus::NotDeposited,
-                CustomError::AlreadyDeposited
-            );
-            challenge.nft2_status = NFTStatus::Deposited;
-        } else {
-            return err!(CustomError::InvalidPayer);
-        }
-    
-        let cpi_accounts = token::Transfer {
-            from: ctx.accounts.from_token_account.to_account_info(),
-            to: ctx.accounts.escrow_token_account.to_account_info(),
-            authority: ctx.accounts.depositor.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        token::transfer(CpiContext::new(cpi_program, cpi_accounts), 1)?;
-
-        if challenge.nft1_status == NFTStatus::Deposited
-            && challenge.nft2_status == NFTStatus::Deposited
-        {
-            challenge.status = ChallengeStatus::Ready;
-        }
-    
+    /**
+     * Withdraw an open challenge nobody has joined yet.
+     * Only p1 can call this function. The challenge is closed and its rent
+     * returned to p1.
+     */
+    pub fn cancel_open_challenge(ctx: Context<CancelOpenChallenge>) -> Result<()> {
+        require!(
+            ctx.accounts.challenge.status == ChallengeStatus::Open,
+            CustomError::InvalidChallengeState
+        );
         Ok(())
     }
 
-    pub fn resolve_challenge(
-        ctx: Context<ResolveChallenge>,
-        vote: u8 // 1 = p1, 2 = p2, 3 = cancel
+    /**
+     * Accept an invitation to a challenge.
+     * Only the invited p2 can call this function. The fee can optionally be
+     * paid in the same call, and NFTs deposited by passing
+     * (source token account, escrow, mint) triples as remaining accounts.
+     * While the collection allowlist is enforced each triple is followed by
+     * the NFT's metadata and its collection's allowlist entry.
+     */
+    pub fn accept_challenge<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AcceptChallenge<'info>>,
+        pay_service_fee: bool,
     ) -> Result<()> {
         let config = &ctx.accounts.config;
+        require!(!config.is_paused(Config::PAUSE_CREATION), CustomError::Paused);
+
         let challenge = &mut ctx.accounts.challenge;
-        let mod_key = &ctx.accounts.moderator.key();
-    
-        // Validate signer is in the list of trusted moderators
         require!(
-            config.moderator_signers.contains(mod_key),
-            CustomError::UnauthorizedModeratorSigner
+            challenge.status == ChallengeStatus::Invited,
+            CustomError::InvalidChallengeState
         );
-    
-        // Ensure the moderator hasn't already voted
         require!(
-            !challenge.moderator_votes.contains(mod_key),
-            CustomError::AlreadyVoted
+            Clock::get()?.unix_timestamp <= challenge.deadlines.fee,
+            CustomError::DeadlinePassed
         );
-    
-        if vote != 3 {
-            // Must be in Ready state if not cancelling
-            require!(
-                challenge.status == ChallengeStatus::Ready,
-                CustomError::InvalidChallengeState
-            );
-        }
-    
-        // Record the vote
-        challenge.moderator_votes.push(mod_key.clone());
-        match vote {
-            1 => challenge.votes_for_p1 += 1,
-            2 => challenge.votes_for_p2 += 1,
-            3 => challenge.votes_to_cancel += 1,
-            _ => return err!(CustomError::UnknownVoteType),
+
+        challenge.status = ChallengeStatus::PendingFee;
+        challenge.refresh_status();
+
+        if pay_service_fee && !challenge.p2_paid {
+            require!(!config.is_paused(Config::PAUSE_DEPOSITS), CustomError::Paused);
+            pay_fee(challenge, &ctx.accounts.p2)?;
         }
-    
-        // Apply resolution threshold
-        let threshold = 3;
-        if challenge.votes_for_p1 >= threshold {
-            challenge.status = ChallengeStatus::Completed;
-            challenge.winner = Some(challenge.p1);
-        } else if challenge.votes_for_p2 >= threshold {
-            challenge.status = ChallengeStatus::Completed;
-            challenge.winner = Some(challenge.p2);
-        } else if challenge.votes_to_cancel >= threshold {
-            challenge.status = ChallengeStatus::Cancelled;
-            challenge.cancelled = true;
+
+        if !ctx.remaining_accounts.is_empty() {
+            require!(!config.is_paused(Config::PAUSE_DEPOSITS), CustomError::Paused);
+            escrow_remaining_nfts(
+                challenge,
+                &ctx.accounts.p2,
+                ctx.remaining_accounts,
+                config.enforce_collection_allowlist,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+            )?;
         }
-    
+
         Ok(())
     }
 
-    pub fn claim_winner_nfts(ctx: Context<ClaimWinnerNfts>) -> Result<()> {
-        let claimer = ctx.accounts.claimer.key();
-    
+    /**
+     * Decline an invitation to a challenge.
+     * Only the invited p2 can call this function. The challenge is closed
+     * and its rent returned to p1.
+     */
+    pub fn decline_challenge(ctx: Context<DeclineChallenge>) -> Result<()> {
         require!(
-            ctx.accounts.challenge.status == ChallengeStatus::Completed,
+            ctx.accounts.challenge.status == ChallengeStatus::Invited,
             CustomError::InvalidChallengeState
         );
+        Ok(())
+    }
+
+    /**
+     * Propose new stakes and fees for a challenge that isn't Ready yet.
+     * Either player can call this, the API co-signs the new fees. A new
+     * proposal replaces the previous one. Remaining accounts are the NFT
+     * metadata accounts the challenge's rules need, as for
+     * `initialize_challenge`. Value tier challenges can't be renegotiated
+     * since the attestation only covers the original stakes.
+     * The new terms can't drop a deposited programmable or locked NFT,
+     * compressed NFT or Core asset: handing those back needs Token Metadata,
+     * Bubblegum or mpl-core accounts of their own, so their owner withdraws
+     * them first or the players cancel the challenge.
+     */
+    pub fn propose_stake_change(ctx: Context<ProposeStakeChange>, change: StakeChange) -> Result<()> {
+        let config = &ctx.accounts.config;
+        require!(!config.is_paused(Config::PAUSE_CREATION), CustomError::Paused);
         require!(
-            Some(claimer) == ctx.accounts.challenge.winner,
-            CustomError::InvalidPayer
+            config.api_signers.contains(&ctx.accounts.api_signer.key()),
+            CustomError::UnauthorizedApiSigner
         );
+
+        let challenge = &mut ctx.accounts.challenge;
+        let proposer = ctx.accounts.proposer.key();
+        require!(challenge.is_player(&proposer), CustomError::InvalidPayer);
+        require!(challenge.is_negotiable(), CustomError::InvalidChallengeState);
         require!(
-            ctx.accounts.challenge.nft1_status == NFTStatus::Deposited
-                && ctx.accounts.challenge.nft2_status == NFTStatus::Deposited,
-            CustomError::InvalidChallengeState
+            !matches!(challenge.match_rule, MatchRule::ValueTier { .. }),
+            CustomError::MatchRuleNotMet
+        );
+        check_stake_terms(
+            config,
+            &change.p1_stake,
+            &change.p2_stake,
+            &challenge.match_rule,
+            ctx.remaining_accounts,
+        )?;
+        require!(
+            !challenge.p1_stake.drops_unreturnable(&change.p1_stake)
+                && !challenge.p2_stake.drops_unreturnable(&change.p2_stake),
+            CustomError::UnreturnableDeposit
         );
 
-        let challenge_seeds = &[
-            b"challenge",
-            ctx.accounts.challenge.p1.as_ref(),
-            &ctx.accounts.challenge.challenge_id.to_le_bytes(),
-            &[ctx.accounts.challenge.bump],
-        ];
-        let signer = &[&challenge_seeds[..]];
-    
-        // Transfer NFT1
-        let cpi_accounts1 = token::Transfer {
-            from: ctx.accounts.escrow_nft1_account.to_account_info(),
-            to: ctx.accounts.winner_nft1_account.to_account_info(),
-            authority: ctx.accounts.challenge.to_account_info(),
+        challenge.proposed_change = Some(ProposedStakeChange {
+            proposer,
+            change: change.clone(),
+        });
+        emit!(StakeChangeProposed {
+            challenge: challenge.key(),
+            proposer,
+            change,
+        });
+
+        Ok(())
+    }
+
+    /**
+     * Accept the other player's proposed stake change.
+     * `expected` must match the proposal, so it can't be swapped before this
+     * lands. Deposits the new terms drop are returned to their owner, pass
+     * their accounts as remaining accounts, p1's first:
+     * - NFTs and token stakes as (escrow, owner token account, mint) triples
+     * - SOL stakes as the `SolEscrow` account alone
+     * The token program must own every returned escrow. Fees already paid are
+     * refunded when a fee changes.
+     */
+    pub fn accept_stake_change<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AcceptStakeChange<'info>>,
+        expected: StakeChange,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CREATION),
+            CustomError::Paused
+        );
+        let challenge = &ctx.accounts.challenge;
+        require!(challenge.is_negotiable(), CustomError::InvalidChallengeState);
+        let accepter = ctx.accounts.accepter.key();
+        require!(challenge.is_player(&accepter), CustomError::InvalidPayer);
+        let Some(ProposedStakeChange { proposer, change }) = challenge.proposed_change.clone() else {
+            return err!(CustomError::NoStakeChange);
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        token::transfer(
-            CpiContext::new_with_signer(cpi_program.clone(), cpi_accounts1, signer),
-            1
+        require!(
+            proposer != accepter && change == expected,
+            CustomError::NoStakeChange
+        );
+
+        let mut returns = ctx.remaining_accounts;
+        let p1_stake = restake(
+            challenge,
+            &ctx.accounts.p1,
+            &challenge.p1_stake,
+            change.p1_stake.clone(),
+            &mut returns,
+            &ctx.accounts.token_program,
         )?;
-    
-        // Transfer NFT2
-        let cpi_accounts2 = token::Transfer {
-            from: ctx.accounts.escrow_nft2_account.to_account_info(),
-            to: ctx.accounts.winner_nft2_account.to_account_info(),
-            authority: ctx.accounts.challenge.to_account_info(),
-        };
-        token::transfer(
-            CpiContext::new_with_signer(
-                cpi_program, cpi_accounts2, signer
-            ),
-            1
+        let p2_stake = restake(
+            challenge,
+            &ctx.accounts.p2,
+            &challenge.p2_stake,
+            change.p2_stake.clone(),
+            &mut returns,
+            &ctx.accounts.token_program,
         )?;
-    
+        require!(returns.is_empty(), CustomError::InvalidRemainingAccounts);
+
+        let previous = StakeChange {
+            p1_fee: challenge.p1_fee,
+            p2_fee: challenge.p2_fee,
+            p1_stake: challenge.p1_stake.terms(),
+            p2_stake: challenge.p2_stake.terms(),
+        };
         let challenge = &mut ctx.accounts.challenge;
-        challenge.nft1_status = NFTStatus::Claimed;
-        challenge.nft2_status = NFTStatus::Claimed;
-    
+        if change.p1_fee != challenge.p1_fee {
+            if challenge.p1_paid && challenge.p1_fee > 0 {
+                challenge.sub_lamports(challenge.p1_fee)?;
+                ctx.accounts.p1.add_lamports(challenge.p1_fee)?;
+            }
+            challenge.p1_fee = change.p1_fee;
+            challenge.p1_paid = change.p1_fee == 0; // Mark as paid if fee is 0
+        }
+        if change.p2_fee != challenge.p2_fee {
+            if challenge.p2_paid && challenge.p2_fee > 0 {
+                challenge.sub_lamports(challenge.p2_fee)?;
+                ctx.accounts.p2.add_lamports(challenge.p2_fee)?;
+            }
+            challenge.p2_fee = change.p2_fee;
+            challenge.p2_paid = change.p2_fee == 0; // Mark as paid if fee is 0
+        }
+        challenge.p1_stake = p1_stake;
+        challenge.p2_stake = p2_stake;
+        challenge.proposed_change = None;
+        challenge.refresh_status();
+
+        emit!(StakeChangeAccepted {
+            challenge: challenge.key(),
+            accepter,
+            previous,
+            change,
+        });
+
         Ok(())
     }
 
-    pub fn claim_refund_nft(ctx: Context<ClaimRefundNft>) -> Result<()> {
-        let claimer = ctx.accounts.claimer.key();
-    
+    /**
+     * Take escrowed stakes back while the opponent has deposited nothing.
+     * Pass the accounts of each deposit to withdraw as remaining accounts:
+     * - NFTs and token stakes as (escrow, player token account, mint) triples
+     * - SOL stakes as the `SolEscrow` account alone
+     * Withdrawn stakes go back to undeposited and can be deposited again
+     * before the escrow deadline. Programmable NFTs, compressed NFTs and Core
+     * assets are withdrawn with their claim refund instructions, locked NFTs
+     * with `unlock_nft`.
+     */
+    pub fn withdraw_deposit<'info>(
+        ctx: Context<'_, '_, 'info, 'info, WithdrawDeposit<'info>>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let player = ctx.accounts.player.to_account_info();
         require!(
-            ctx.accounts.challenge.status == ChallengeStatus::Cancelled,
+            ctx.accounts.challenge.can_withdraw(&player.key()),
             CustomError::InvalidChallengeState
         );
         require!(
-            ctx.accounts.destination_token_account.owner == claimer,
-            CustomError::InvalidPayer
+            !ctx.remaining_accounts.is_empty(),
+            CustomError::InvalidRemainingAccounts
+        );
+
+        let mut remaining = ctx.remaining_accounts;
+        while !remaining.is_empty() {
+            return_deposit(
+                &mut ctx.accounts.challenge,
+                &[&player],
+                &mut remaining,
+                NFTStatus::NotDeposited,
+                &ctx.accounts.token_program,
+            )?;
+        }
+        ctx.accounts.challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Cancel a challenge both players agree to call off, at any point before
+     * it is resolved. Needs both p1 and p2 to sign. Paid fees are refunded and
+     * escrowed stakes of either player are returned, pass them as remaining
+     * accounts the way `withdraw_deposit` takes them. Programmable NFTs,
+     * compressed NFTs and Core assets are refunded afterwards with their claim
+     * refund instructions, locked NFTs with `unlock_nft`.
+     */
+    pub fn mutual_cancel<'info>(
+        ctx: Context<'_, '_, 'info, 'info, MutualCancel<'info>>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_RESOLUTION | Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            challenge.is_negotiable() || challenge.status == ChallengeStatus::Ready,
+            CustomError::InvalidChallengeState
+        );
+
+        challenge.status = ChallengeStatus::Cancelled;
+        challenge.cancelled = true;
+
+        if challenge.p1_paid && challenge.p1_fee > 0 {
+            challenge.p1_fee_refunded = true;
+            challenge.sub_lamports(challenge.p1_fee)?;
+            ctx.accounts.p1.add_lamports(challenge.p1_fee)?;
+        }
+        if challenge.p2_paid && challenge.p2_fee > 0 {
+            challenge.p2_fee_refunded = true;
+            challenge.sub_lamports(challenge.p2_fee)?;
+            ctx.accounts.p2.add_lamports(challenge.p2_fee)?;
+        }
+
+        let p1 = ctx.accounts.p1.to_account_info();
+        let p2 = ctx.accounts.p2.to_account_info();
+        let mut remaining = ctx.remaining_accounts;
+        while !remaining.is_empty() {
+            return_deposit(
+                &mut ctx.accounts.challenge,
+                &[&p1, &p2],
+                &mut remaining,
+                NFTStatus::Refunded,
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn pay_service_fee(ctx: Context<PayServiceFee>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        pay_fee(&mut ctx.accounts.challenge, &ctx.accounts.payer)
+    }
+
+    /**
+     * Deposit one of the depositor's declared NFTs into escrow.
+     * More NFTs from the same bundle can be deposited in the same call by
+     * passing (source token account, escrow, mint) triples as remaining accounts.
+     * While the collection allowlist is enforced the named NFT's metadata and
+     * allowlist entry are required, and each triple is followed by the bundle
+     * NFT's metadata and allowlist entry.
+     */
+    pub fn deposit_nft<'info>(ctx: Context<'_, '_, 'info, 'info, DepositNft<'info>>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        let allowlist = ctx.accounts.config.enforce_collection_allowlist;
+        if allowlist {
+            check_nft_allowlisted(
+                ctx.accounts.nft_metadata.as_ref().map(|metadata| metadata.as_ref()),
+                ctx.accounts.allowed_collection.as_ref().map(|allowed| allowed.as_ref()),
+                &ctx.accounts.nft_mint.key(),
+            )?;
+        }
+        escrow_nft(
+            &mut ctx.accounts.challenge,
+            &ctx.accounts.depositor,
+            &ctx.accounts.from_token_account,
+            &ctx.accounts.escrow_token_account.to_account_info(),
+            &ctx.accounts.nft_mint,
+            &ctx.accounts.token_program,
+        )?;
+        escrow_remaining_nfts(
+            &mut ctx.accounts.challenge,
+            &ctx.accounts.depositor,
+            ctx.remaining_accounts,
+            allowlist,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    pub fn resolve_challenge(
+        ctx: Context<ResolveChallenge>,
+        vote: u8 // 1 = p1, 2 = p2, 3 = cancel, 4 = draw
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let challenge = &mut ctx.accounts.challenge;
+        let mod_key = &ctx.accounts.moderator.key();
+        require!(!config.is_paused(Config::PAUSE_RESOLUTION), CustomError::Paused);
+    
+        // Validate signer is in the list of trusted moderators
+        require!(
+            config.moderator_signers.contains(mod_key),
+            CustomError::UnauthorizedModeratorSigner
+        );
+    
+        // Ensure the moderator hasn't already voted
+        require!(
+            !challenge.moderator_votes.contains(mod_key),
+            CustomError::AlreadyVoted
         );
     
-        if claimer == ctx.accounts.challenge.p1 {
+        // Resolved challenges can't be overturned by late votes
+        require!(!challenge.is_resolved(), CustomError::InvalidChallengeState);
+        // Open challenges have no p2 yet, p1 withdraws them with `cancel_open_challenge`
+        require!(
+            challenge.status != ChallengeStatus::Open,
+            CustomError::InvalidChallengeState
+        );
+
+        if vote != 3 {
+            // Must be in Ready state if not cancelling
             require!(
-                ctx.accounts.challenge.nft1_status == NFTStatus::Deposited,
+                challenge.status == ChallengeStatus::Ready,
                 CustomError::InvalidChallengeState
             );
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    token::Transfer {
-                        from: ctx.accounts.escrow_nft_account.to_account_info(),
-                        to: ctx.accounts.destination_token_account.to_account_info(),
-                        authority: ctx.accounts.challenge.to_account_info(),
-                    },
-                ),
-                1,
-            )?;
-
-            let challenge = &mut ctx.accounts.challenge;
-            challenge.nft1_status = NFTStatus::Refunded;
-        } else if claimer == ctx.accounts.challenge.p2 {
+            // Past the deadline the challenge can only be cancelled or expired
             require!(
-                ctx.accounts.challenge.nft2_status == NFTStatus::Deposited,
-                CustomError::InvalidChallengeState
+                Clock::get()?.unix_timestamp <= challenge.deadlines.resolution,
+                CustomError::DeadlinePassed
             );
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    token::Transfer {
-                        from: ctx.accounts.escrow_nft_account.to_account_info(),
-                        to: ctx.accounts.destination_token_account.to_account_info(),
-                        authority: ctx.accounts.challenge.to_account_info(),
-                    },
-                ),
-                1,
-            )?;
+        }
+    
+        // Record the vote
+        challenge.moderator_votes.push(*mod_key);
+        match vote {
+            1 => challenge.votes_for_p1 += 1,
+            2 => challenge.votes_for_p2 += 1,
+            3 => challenge.votes_to_cancel += 1,
+            4 => challenge.votes_for_draw += 1,
+            _ => return err!(CustomError::UnknownVoteType),
+        }
+    
+        // Apply the threshold snapshotted when the challenge was created
+        let threshold = challenge.resolution_threshold;
+        if challenge.votes_for_p1 >= threshold {
+            challenge.status = ChallengeStatus::Completed;
+            challenge.winner = Some(challenge.p1);
+        } else if challenge.votes_for_p2 >= threshold {
+            challenge.status = ChallengeStatus::Completed;
+            challenge.winner = Some(challenge.p2);
+        } else if challenge.votes_for_draw >= threshold {
+            challenge.status = ChallengeStatus::Drawn;
+            challenge.drawn = true;
+        } else if challenge.votes_to_cancel >= threshold {
+            challenge.status = ChallengeStatus::Cancelled;
+            challenge.cancelled = true;
+        }
+    
+        Ok(())
+    }
+
+    /**
+     * Cancel a challenge whose current stage deadline has passed.
+     * Anyone can call this so that deposits never stay stuck in escrow.
+     */
+    pub fn expire_challenge(ctx: Context<ExpireChallenge>) -> Result<()> {
+        let challenge = &mut ctx.accounts.challenge;
+        let deadline = match challenge.status {
+            ChallengeStatus::Invited | ChallengeStatus::PendingFee => challenge.deadlines.fee,
+            ChallengeStatus::PendingEscrow => challenge.deadlines.escrow,
+            ChallengeStatus::Ready => challenge.deadlines.resolution,
+            _ => return err!(CustomError::InvalidChallengeState),
+        };
+        require!(
+            Clock::get()?.unix_timestamp > deadline,
+            CustomError::DeadlineNotReached
+        );
+
+        challenge.status = ChallengeStatus::Cancelled;
+        challenge.cancelled = true;
+
+        Ok(())
+    }
+
+    /**
+     * Send the fees held on a completed challenge to the treasury.
+     * Also applies to drawn challenges when the draw fee policy keeps fees.
+     * Anyone can call this function.
+     */
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(challenge.fees_collectable(), CustomError::InvalidChallengeState);
+        require!(!challenge.fees_collected, CustomError::FeesAlreadyCollected);
+
+        let amount = challenge
+            .p1_fee
+            .checked_add(challenge.p2_fee)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        challenge.fees_collected = true;
+        if amount > 0 {
+            challenge.sub_lamports(amount)?;
+            ctx.accounts.treasury.add_lamports(amount)?;
+        }
+
+        Ok(())
+    }
 
-            let challenge = &mut ctx.accounts.challenge;
-            challenge.nft2_status = NFTStatus::Refunded;
+    /**
+     * Return a player's fee from a cancelled challenge.
+     * Also applies to drawn challenges when the draw fee policy refunds fees.
+     * Only p1 or p2 can call this function, for their own fee.
+     */
+    pub fn claim_fee_refund(ctx: Context<ClaimFeeRefund>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        let claimer = ctx.accounts.claimer.key();
+        require!(challenge.fees_refundable(), CustomError::InvalidChallengeState);
+
+        let amount = if claimer == challenge.p1 {
+            require!(
+                challenge.p1_paid && challenge.p1_fee > 0 && !challenge.p1_fee_refunded,
+                CustomError::NoFeeToRefund
+            );
+            challenge.p1_fee_refunded = true;
+            challenge.p1_fee
+        } else if claimer == challenge.p2 {
+            require!(
+                challenge.p2_paid && challenge.p2_fee > 0 && !challenge.p2_fee_refunded,
+                CustomError::NoFeeToRefund
+            );
+            challenge.p2_fee_refunded = true;
+            challenge.p2_fee
         } else {
             return err!(CustomError::InvalidPayer);
-        }
-    
+        };
+
+        challenge.sub_lamports(amount)?;
+        ctx.accounts.claimer.add_lamports(amount)?;
+
         Ok(())
     }
 
+    /**
+     * Transfer both players' escrowed stakes to the winner.
+     * Pass the escrows to pay out as remaining accounts:
+     * - NFTs and token stakes as (escrow, winner token account, mint) triples,
+     *   the winner's associated token accounts are created if needed
+     * - SOL stakes as the `SolEscrow` account alone
+     * Large bundles can be claimed over several calls. Programmable NFTs,
+     * compressed NFTs and Core assets are claimed with their own instructions.
+     */
+    pub fn claim_winnings<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClaimWinnings<'info>>,
+    ) -> Result<()> {
+        let claimer = ctx.accounts.claimer.key();
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
 
-}
+        require!(
+            ctx.accounts.challenge.status == ChallengeStatus::Completed,
+            CustomError::InvalidChallengeState
+        );
+        require!(
+            Some(claimer) == ctx.accounts.challenge.winner,
+            CustomError::InvalidPayer
+        );
+        require!(
+            !ctx.remaining_accounts.is_empty(),
+            CustomError::InvalidRemainingAccounts
+        );
 
-#[account]
-pub struct Config {
-    pub admin: Pubkey,
-    pub bump: u8,
-    pub treasury: Pubkey,
-    pub chain_id: [u8; 16], // chain identifier (16 bytes)
-    pub api_signers: Vec<Pubkey>,
-    pub moderator_signers: Vec<Pubkey>,
-}
+        // Token and SOL stakes are recognised by their escrow address, anything else is an NFT
+        let challenge_key = ctx.accounts.challenge.key();
+        let fungible_escrows = [
+            (ctx.accounts.challenge.p1, &ctx.accounts.challenge.p1_stake),
+            (ctx.accounts.challenge.p2, &ctx.accounts.challenge.p2_stake),
+        ]
+        .into_iter()
+        .filter_map(|(player, stake)| match stake {
+            Stake::Token { .. } => Some((token_escrow_address(&challenge_key, &player), player)),
+            Stake::Sol { .. } => Some((sol_escrow_address(&challenge_key, &player), player)),
+            Stake::Nft { .. } | Stake::Cnft { .. } | Stake::Core { .. } => None,
+        })
+        .collect::<Vec<_>>();
 
-impl Config {
-    pub const MAX_SIGNERS: usize = 10;
-    pub const MAX_SIZE: usize = 32 + 1 + 32 + 16 + (32 * Self::MAX_SIGNERS * 2) + (4 * 2);
-}
+        let mut remaining = ctx.remaining_accounts;
+        while let Some(escrow) = remaining.first() {
+            let staker = fungible_escrows
+                .iter()
+                .find(|(escrow_key, _)| *escrow_key == escrow.key())
+                .map(|(_, player)| *player);
 
-#[account]
-pub struct Challenge {
-    pub p1: Pubkey,
-    pub p2: Pubkey,
-    pub challenge_id: u64,
-    pub p1_fee: u64,
-    pub p2_fee: u64,
-    pub p1_paid: bool,
-    pub p2_paid: bool,
-    pub nft1_mint: Pubkey,
-    pub nft2_mint: Pubkey,
-    pub nft1_status: NFTStatus,
-    pub nft2_status: NFTStatus,
-    pub bump: u8,
-    pub status: ChallengeStatus,
-    pub winner: Option<Pubkey>,
-    pub cancelled: bool,
-    pub moderator_votes: Vec<Pubkey>,
-    pub votes_for_p1: u8,
-    pub votes_for_p2: u8,
-    pub votes_to_cancel: u8,
-}
+            if let Some(staker) = staker {
+                if let Some(Stake::Sol { lamports, status }) =
+                    ctx.accounts.challenge.stake_mut(&staker)
+                {
+                    require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+                    *status = NFTStatus::Claimed;
+                    escrow.sub_lamports(*lamports)?;
+                    ctx.accounts.claimer.add_lamports(*lamports)?;
+                    remaining = &remaining[1..];
+                    continue;
+                }
+            }
 
-impl Challenge {
-    pub const LEN: usize = 555;
-}
+            require!(remaining.len() >= 3, CustomError::InvalidRemainingAccounts);
+            let (destination, mint) = (&remaining[1], &remaining[2]);
+            remaining = &remaining[3..];
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-#[repr(u8)]
-pub enum ChallengeStatus {
-    PendingFee,
-    PendingEscrow,
-    Ready,
-    Completed,
-    Cancelled
-}
+            associated_token::create_idempotent(CpiContext::new(
+                ctx.accounts.associated_token_program.to_account_info(),
+                associated_token::Create {
+                    payer: ctx.accounts.claimer.to_account_info(),
+                    associated_token: destination.clone(),
+                    authority: ctx.accounts.claimer.to_account_info(),
+                    mint: mint.clone(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+            ))?;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-#[repr(u8)]
+            let Some(staker) = staker else {
+                let staked_nft = ctx
+                    .accounts
+                    .challenge
+                    .staked_nft_mut(&mint.key())
+                    .ok_or(CustomError::InvalidNftMint)?;
+                require!(
+                    staked_nft.status == NFTStatus::Deposited,
+                    CustomError::InvalidChallengeState
+                );
+                require!(!staked_nft.programmable, CustomError::ProgrammableNftTransfer);
+                staked_nft.status = NFTStatus::Claimed;
+
+                release_nft(
+                    &ctx.accounts.challenge,
+                    escrow,
+                    destination,
+                    mint,
+                    &ctx.accounts.token_program,
+                )?;
+                continue;
+            };
+
+            let Some(Stake::Token {
+                mint: stake_mint,
+                status,
+                escrowed,
+                ..
+            }) = ctx.accounts.challenge.stake_mut(&staker)
+            else {
+                return err!(CustomError::InvalidStakeType);
+            };
+            require_keys_eq!(*stake_mint, mint.key(), CustomError::InvalidStakeMint);
+            require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+            *status = NFTStatus::Claimed;
+            let amount = *escrowed;
+            let decimals = InterfaceAccount::<Mint>::try_from(mint)?.decimals;
+
+            release_tokens(
+                &ctx.accounts.challenge,
+                escrow,
+                destination,
+                mint,
+                &ctx.accounts.token_program,
+                amount,
+                decimals,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Return a player's own NFTs from a cancelled or drawn challenge.
+     * More NFTs from the claimer's bundle can be refunded in the same call by
+     * passing (escrow, destination token account, mint) triples as remaining accounts.
+     */
+    pub fn claim_refund_nft<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClaimRefundNft<'info>>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        require!(
+            ctx.remaining_accounts.chunks_exact(3).remainder().is_empty(),
+            CustomError::InvalidRemainingAccounts
+        );
+
+        let nft_mint = ctx.accounts.nft_mint.to_account_info();
+        let escrow = ctx.accounts.escrow_nft_account.to_account_info();
+        let destination = ctx.accounts.destination_token_account.to_account_info();
+        let named = [escrow, destination, nft_mint];
+
+        for accounts in std::iter::once(&named[..]).chain(ctx.remaining_accounts.chunks(3)) {
+            let (escrow, destination, nft_mint) = (&accounts[0], &accounts[1], &accounts[2]);
+
+            let destination_account =
+                TokenAccount::try_deserialize(&mut &destination.try_borrow_data()?[..])?;
+            require!(
+                destination_account.owner == ctx.accounts.claimer.key(),
+                CustomError::InvalidPayer
+            );
+            ctx.accounts
+                .challenge
+                .refund_nft(&ctx.accounts.claimer.key(), &nft_mint.key())?;
+
+            release_nft(
+                &ctx.accounts.challenge,
+                escrow,
+                destination,
+                nft_mint,
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Deposit one of the depositor's declared programmable NFTs into escrow.
+     * pNFT token accounts are frozen, so the NFT is moved with Token Metadata
+     * `Transfer` into the challenge PDA's associated token account.
+     * While the collection allowlist is enforced the allowlist entry of the
+     * pNFT's collection is required.
+     */
+    pub fn deposit_pnft(ctx: Context<PnftEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        if ctx.accounts.config.enforce_collection_allowlist {
+            check_nft_allowlisted(
+                Some(ctx.accounts.metadata.as_ref()),
+                ctx.accounts.allowed_collection.as_ref().map(|allowed| allowed.as_ref()),
+                &ctx.accounts.nft_mint.key(),
+            )?;
+        }
+        let from_token_account = TokenAccount::try_deserialize(
+            &mut &ctx.accounts.player_token_account.try_borrow_data()?[..],
+        )?;
+        mark_nft_deposited(
+            &mut ctx.accounts.challenge,
+            &ctx.accounts.player,
+            &from_token_account,
+            &ctx.accounts.nft_mint,
+        )?
+        .programmable = true;
+
+        transfer_pnft(ctx.accounts, true)?;
+
+        ctx.accounts.challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Transfer one escrowed programmable NFT to the winner.
+     */
+    pub fn claim_winner_pnft(ctx: Context<PnftEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            challenge.status == ChallengeStatus::Completed,
+            CustomError::InvalidChallengeState
+        );
+        require!(
+            Some(ctx.accounts.player.key()) == challenge.winner,
+            CustomError::InvalidPayer
+        );
+
+        let staked_nft = challenge
+            .staked_nft_mut(&ctx.accounts.nft_mint.key())
+            .ok_or(CustomError::InvalidNftMint)?;
+        require!(
+            staked_nft.status == NFTStatus::Deposited && staked_nft.programmable,
+            CustomError::InvalidChallengeState
+        );
+        staked_nft.status = NFTStatus::Claimed;
+
+        transfer_pnft(ctx.accounts, false)
+    }
+
+    /**
+     * Return one of a player's own programmable NFTs from a cancelled or drawn challenge.
+     * Also withdraws it while the opponent has deposited nothing, as `withdraw_deposit` does.
+     */
+    pub fn claim_refund_pnft(ctx: Context<PnftEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let player = ctx.accounts.player.key();
+        let challenge = &mut ctx.accounts.challenge;
+        let returned_status = challenge.refund_status(&player)?;
+
+        let staked_nft = challenge
+            .nfts_mut(&player)?
+            .iter_mut()
+            .find(|nft| nft.mint == ctx.accounts.nft_mint.key())
+            .ok_or(CustomError::InvalidNftMint)?;
+        require!(
+            staked_nft.status == NFTStatus::Deposited && staked_nft.programmable,
+            CustomError::InvalidChallengeState
+        );
+        staked_nft.status = returned_status;
+        challenge.refresh_status();
+
+        transfer_pnft(ctx.accounts, false)
+    }
+
+    /**
+     * Lock one of the depositor's declared NFTs in their own wallet instead of
+     * escrowing it. The challenge PDA is approved as delegate and freezes the
+     * token account through Token Metadata, so only Metaplex NFTs with a
+     * master edition can be locked. While the collection allowlist is
+     * enforced the NFT's metadata and allowlist entry are required.
+     */
+    pub fn lock_nft(ctx: Context<LockNft>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        if ctx.accounts.config.enforce_collection_allowlist {
+            check_nft_allowlisted(
+                ctx.accounts.nft_metadata.as_ref().map(|metadata| metadata.as_ref()),
+                ctx.accounts.allowed_collection.as_ref().map(|allowed| allowed.as_ref()),
+                &ctx.accounts.nft_mint.key(),
+            )?;
+        }
+        let staked_nft = mark_nft_deposited(
+            &mut ctx.accounts.challenge,
+            &ctx.accounts.player,
+            &ctx.accounts.player_token_account,
+            &ctx.accounts.nft_mint,
+        )?;
+        staked_nft.status = NFTStatus::Locked;
+        staked_nft.locked = true;
+
+        token_interface::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::Approve {
+                    to: ctx.accounts.player_token_account.to_account_info(),
+                    delegate: ctx.accounts.challenge.to_account_info(),
+                    authority: ctx.accounts.player.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+        set_nft_frozen(ctx.accounts, true)?;
+
+        ctx.accounts.challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Thaw a player's own locked NFT once the challenge is over and revoke the
+     * challenge's delegation. Used for refunds and by the winner for their own NFT.
+     * Also withdraws the lock early while the opponent has deposited nothing.
+     */
+    pub fn unlock_nft(ctx: Context<LockNft>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let player = ctx.accounts.player.key();
+        let challenge = &mut ctx.accounts.challenge;
+        let unlocked_status = match challenge.status {
+            ChallengeStatus::Cancelled | ChallengeStatus::Drawn => NFTStatus::Refunded,
+            ChallengeStatus::Completed if challenge.winner == Some(player) => NFTStatus::Claimed,
+            _ if challenge.can_withdraw(&player) => NFTStatus::NotDeposited,
+            _ => return err!(CustomError::InvalidChallengeState),
+        };
+
+        let staked_nft = challenge
+            .nfts_mut(&player)?
+            .iter_mut()
+            .find(|nft| nft.mint == ctx.accounts.nft_mint.key())
+            .ok_or(CustomError::InvalidNftMint)?;
+        require!(
+            staked_nft.status == NFTStatus::Locked,
+            CustomError::InvalidChallengeState
+        );
+        // A withdrawn NFT may be deposited again, possibly into escrow this time
+        staked_nft.locked = unlocked_status != NFTStatus::NotDeposited;
+        staked_nft.status = unlocked_status;
+        challenge.refresh_status();
+
+        set_nft_frozen(ctx.accounts, false)?;
+        token_interface::revoke(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token_interface::Revoke {
+                source: ctx.accounts.player_token_account.to_account_info(),
+                authority: ctx.accounts.player.to_account_info(),
+            },
+        ))
+    }
+
+    /**
+     * Transfer the loser's locked NFT to the winner.
+     * The token account is thawed and the NFT moved with the challenge's delegate authority.
+     */
+    pub fn claim_locked_nft(ctx: Context<ClaimLockedNft>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            challenge.status == ChallengeStatus::Completed,
+            CustomError::InvalidChallengeState
+        );
+        let winner = ctx.accounts.winner.key();
+        require!(Some(winner) == challenge.winner, CustomError::InvalidPayer);
+        let loser = if winner == challenge.p1 { challenge.p2 } else { challenge.p1 };
+        require_keys_eq!(
+            ctx.accounts.locked_token_account.owner,
+            loser,
+            CustomError::SourceOwnerMismatch
+        );
+
+        let staked_nft = challenge
+            .nfts_mut(&loser)?
+            .iter_mut()
+            .find(|nft| nft.mint == ctx.accounts.nft_mint.key())
+            .ok_or(CustomError::InvalidNftMint)?;
+        require!(
+            staked_nft.status == NFTStatus::Locked,
+            CustomError::InvalidChallengeState
+        );
+        staked_nft.status = NFTStatus::Claimed;
+
+        let challenge = &ctx.accounts.challenge;
+        let signer_seeds = challenge_signer_seeds(challenge);
+        let challenge_seeds = signer_seeds.as_slices();
+        let token_metadata_program = ctx.accounts.token_metadata_program.to_account_info();
+        let challenge_info = challenge.to_account_info();
+        let locked_token_account = ctx.accounts.locked_token_account.to_account_info();
+        let edition = ctx.accounts.edition.to_account_info();
+        let nft_mint = ctx.accounts.nft_mint.to_account_info();
+        let token_program = ctx.accounts.token_program.to_account_info();
+        ThawDelegatedAccountCpiBuilder::new(&token_metadata_program)
+            .delegate(&challenge_info)
+            .token_account(&locked_token_account)
+            .edition(&edition)
+            .mint(&nft_mint)
+            .token_program(&token_program)
+            .invoke_signed(&[&challenge_seeds[..]])?;
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                token_program,
+                token_interface::TransferChecked {
+                    from: locked_token_account,
+                    mint: nft_mint,
+                    to: ctx.accounts.destination_token_account.to_account_info(),
+                    authority: challenge_info,
+                },
+                &[&challenge_seeds[..]],
+            ),
+            1,
+            0,
+        )
+    }
+
+    /**
+     * Deposit the depositor's compressed NFT stake into escrow.
+     * Bubblegum transfers the leaf to the challenge PDA, pass the leaf's
+     * Merkle proof as remaining accounts. The leaf must be delegated to its
+     * owner, which is the default.
+     */
+    pub fn deposit_cnft<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CnftEscrow<'info>>,
+        leaf: CnftLeaf,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        // The allowlist may have been enforced after the challenge was created
+        require!(
+            !ctx.accounts.config.enforce_collection_allowlist,
+            CustomError::CnftNotAllowlistable
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        check_deposit_window(challenge)?;
+
+        let Some(Stake::Cnft { merkle_tree, asset_id, status }) =
+            challenge.stake_mut(&ctx.accounts.player.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*merkle_tree, ctx.accounts.merkle_tree.key(), CustomError::InvalidCnftAsset);
+        require_keys_eq!(
+            *asset_id,
+            get_asset_id(merkle_tree, leaf.nonce),
+            CustomError::InvalidCnftAsset
+        );
+        require!(*status == NFTStatus::NotDeposited, CustomError::AlreadyDeposited);
+        *status = NFTStatus::Deposited;
+
+        transfer_cnft(ctx.accounts, ctx.remaining_accounts, &leaf, true)?;
+
+        ctx.accounts.challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Transfer an escrowed compressed NFT to the winner.
+     * Pass the leaf's Merkle proof as remaining accounts.
+     */
+    pub fn claim_winner_cnft<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CnftEscrow<'info>>,
+        leaf: CnftLeaf,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            challenge.status == ChallengeStatus::Completed,
+            CustomError::InvalidChallengeState
+        );
+        require!(
+            Some(ctx.accounts.player.key()) == challenge.winner,
+            CustomError::InvalidPayer
+        );
+
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let status = challenge
+            .cnft_status_mut(&merkle_tree, &get_asset_id(&merkle_tree, leaf.nonce))
+            .ok_or(CustomError::InvalidCnftAsset)?;
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = NFTStatus::Claimed;
+
+        transfer_cnft(ctx.accounts, ctx.remaining_accounts, &leaf, false)
+    }
+
+    /**
+     * Return a player's own compressed NFT from a cancelled or drawn challenge.
+     * Also withdraws it while the opponent has deposited nothing, as `withdraw_deposit` does.
+     * Pass the leaf's Merkle proof as remaining accounts.
+     */
+    pub fn claim_refund_cnft<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CnftEscrow<'info>>,
+        leaf: CnftLeaf,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let player = ctx.accounts.player.key();
+        let challenge = &mut ctx.accounts.challenge;
+        let returned_status = challenge.refund_status(&player)?;
+
+        let Some(Stake::Cnft { merkle_tree, asset_id, status }) = challenge.stake_mut(&player)
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*merkle_tree, ctx.accounts.merkle_tree.key(), CustomError::InvalidCnftAsset);
+        require_keys_eq!(
+            *asset_id,
+            get_asset_id(merkle_tree, leaf.nonce),
+            CustomError::InvalidCnftAsset
+        );
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = returned_status;
+        challenge.refresh_status();
+
+        transfer_cnft(ctx.accounts, ctx.remaining_accounts, &leaf, false)
+    }
+
+    /**
+     * Deposit the depositor's Metaplex Core asset stake into escrow.
+     * mpl-core transfers the asset to the challenge PDA, and the asset's
+     * collection is recorded on the challenge.
+     */
+    pub fn deposit_core(ctx: Context<CoreEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        check_deposit_window(challenge)?;
+
+        let Some(Stake::Core { asset, collection, status }) =
+            challenge.stake_mut(&ctx.accounts.player.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*asset, ctx.accounts.asset.key(), CustomError::InvalidCoreAsset);
+        require!(*status == NFTStatus::NotDeposited, CustomError::AlreadyDeposited);
+        *collection = core_collection(&ctx.accounts.asset.to_account_info())?;
+        if ctx.accounts.config.enforce_collection_allowlist {
+            require!(
+                collection.is_some_and(|collection| ctx
+                    .accounts
+                    .allowed_collection
+                    .as_ref()
+                    .is_some_and(|allowed| allowed.collection == collection)),
+                CustomError::CollectionNotAllowed
+            );
+        }
+        *status = NFTStatus::Deposited;
+
+        transfer_core(ctx.accounts, true)?;
+
+        ctx.accounts.challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Transfer an escrowed Core asset to the winner.
+     */
+    pub fn claim_winner_core(ctx: Context<CoreEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            challenge.status == ChallengeStatus::Completed,
+            CustomError::InvalidChallengeState
+        );
+        require!(
+            Some(ctx.accounts.player.key()) == challenge.winner,
+            CustomError::InvalidPayer
+        );
+
+        let status = challenge
+            .core_status_mut(&ctx.accounts.asset.key())
+            .ok_or(CustomError::InvalidCoreAsset)?;
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = NFTStatus::Claimed;
+
+        transfer_core(ctx.accounts, false)
+    }
+
+    /**
+     * Return a player's own Core asset from a cancelled or drawn challenge.
+     * Also withdraws it while the opponent has deposited nothing, as `withdraw_deposit` does.
+     */
+    pub fn claim_refund_core(ctx: Context<CoreEscrow>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let player = ctx.accounts.player.key();
+        let challenge = &mut ctx.accounts.challenge;
+        let returned_status = challenge.refund_status(&player)?;
+
+        let Some(Stake::Core { asset, status, .. }) = challenge.stake_mut(&player) else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*asset, ctx.accounts.asset.key(), CustomError::InvalidCoreAsset);
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = returned_status;
+        challenge.refresh_status();
+
+        transfer_core(ctx.accounts, false)
+    }
+
+    /**
+     * Deposit the depositor's SPL token stake into escrow.
+     * If the mint charges a transfer fee the escrow receives less than the
+     * stake amount, winnings and refunds pay out what was actually received.
+     */
+    pub fn deposit_tokens(ctx: Context<DepositTokens>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        check_deposit_window(challenge)?;
+
+        let Some(Stake::Token { mint, amount, status, .. }) =
+            challenge.stake_mut(&ctx.accounts.depositor.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*mint, ctx.accounts.mint.key(), CustomError::InvalidStakeMint);
+        require!(*status == NFTStatus::NotDeposited, CustomError::AlreadyDeposited);
+        check_mint_extensions(&ctx.accounts.mint)?;
+        let amount = *amount;
+        let balance_before = ctx.accounts.escrow_token_account.amount;
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::TransferChecked {
+                    from: ctx.accounts.from_token_account.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    to: ctx.accounts.escrow_token_account.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        // A transfer fee is withheld in the escrow, move it to the mint so the escrow can be closed
+        if has_transfer_fee(&ctx.accounts.mint)? {
+            transfer_fee::harvest_withheld_tokens_to_mint(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    transfer_fee::HarvestWithheldTokensToMint {
+                        token_program_id: ctx.accounts.token_program.to_account_info(),
+                        mint: ctx.accounts.mint.to_account_info(),
+                    },
+                ),
+                vec![ctx.accounts.escrow_token_account.to_account_info()],
+            )?;
+        }
+        ctx.accounts.escrow_token_account.reload()?;
+        let received = ctx
+            .accounts
+            .escrow_token_account
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(CustomError::InvalidEscrowAccount)?;
+
+        let challenge = &mut ctx.accounts.challenge;
+        if let Some(Stake::Token { status, escrowed, .. }) =
+            challenge.stake_mut(&ctx.accounts.depositor.key())
+        {
+            *status = NFTStatus::Deposited;
+            *escrowed = received;
+        }
+        challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Deposit the depositor's SOL stake into escrow.
+     */
+    pub fn deposit_sol(ctx: Context<DepositSol>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_DEPOSITS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        check_deposit_window(challenge)?;
+
+        let Some(Stake::Sol { lamports, status }) =
+            challenge.stake_mut(&ctx.accounts.depositor.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require!(*status == NFTStatus::NotDeposited, CustomError::AlreadyDeposited);
+        let lamports = *lamports;
+        *status = NFTStatus::Deposited;
+
+        ctx.accounts.sol_escrow.bump = ctx.bumps.sol_escrow;
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.depositor.to_account_info(),
+                    to: ctx.accounts.sol_escrow.to_account_info(),
+                },
+            ),
+            lamports,
+        )?;
+
+        challenge.refresh_status();
+
+        Ok(())
+    }
+
+    /**
+     * Return a player's own token stake from a cancelled or drawn challenge.
+     */
+    pub fn claim_refund_tokens(ctx: Context<ClaimRefundTokens>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            matches!(
+                challenge.status,
+                ChallengeStatus::Cancelled | ChallengeStatus::Drawn
+            ),
+            CustomError::InvalidChallengeState
+        );
+
+        let Some(Stake::Token {
+            mint,
+            status,
+            escrowed,
+            ..
+        }) = challenge.stake_mut(&ctx.accounts.claimer.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*mint, ctx.accounts.mint.key(), CustomError::InvalidStakeMint);
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        let amount = *escrowed;
+        *status = NFTStatus::Refunded;
+
+        release_tokens(
+            challenge,
+            &ctx.accounts.escrow_token_account.to_account_info(),
+            &ctx.accounts.destination_token_account.to_account_info(),
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.token_program,
+            amount,
+            ctx.accounts.mint.decimals,
+        )
+    }
+
+    /**
+     * Return a player's own SOL stake from a cancelled or drawn challenge.
+     */
+    pub fn claim_refund_sol(ctx: Context<ClaimRefundSol>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge = &mut ctx.accounts.challenge;
+        require!(
+            matches!(
+                challenge.status,
+                ChallengeStatus::Cancelled | ChallengeStatus::Drawn
+            ),
+            CustomError::InvalidChallengeState
+        );
+
+        let Some(Stake::Sol { lamports, status }) =
+            challenge.stake_mut(&ctx.accounts.claimer.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = NFTStatus::Refunded;
+
+        ctx.accounts.sol_escrow.sub_lamports(*lamports)?;
+        ctx.accounts.claimer.add_lamports(*lamports)?;
+
+        Ok(())
+    }
+
+    /**
+     * Close a settled challenge and its escrow accounts.
+     * Pass every escrow that was ever deposited into as remaining accounts,
+     * p1's first, in the order of each stake. Anyone can call this function,
+     * rent goes back to whoever paid it.
+     */
+    pub fn close_challenge<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CloseChallenge<'info>>,
+    ) -> Result<()> {
+        let challenge = &ctx.accounts.challenge;
+        require!(challenge.is_settled(), CustomError::ChallengeNotSettled);
+
+        let signer_seeds = challenge_signer_seeds(challenge);
+        let challenge_seeds = signer_seeds.as_slices();
+        let signer = &[&challenge_seeds[..]];
+
+        // Escrow rent was paid by the player who deposited into it
+        let challenge_key = challenge.key();
+        let escrowed = challenge
+            .p1_stake
+            .used_escrows(&challenge_key, &challenge.p1)
+            .into_iter()
+            .map(|escrow| (escrow, ctx.accounts.p1.to_account_info()))
+            .chain(
+                challenge
+                    .p2_stake
+                    .used_escrows(&challenge_key, &challenge.p2)
+                    .into_iter()
+                    .map(|escrow| (escrow, ctx.accounts.p2.to_account_info())),
+            )
+            .collect::<Vec<_>>();
+        require!(
+            escrowed.len() == ctx.remaining_accounts.len(),
+            CustomError::InvalidRemainingAccounts
+        );
+
+        for ((escrow_key, rent_destination), escrow) in escrowed.into_iter().zip(ctx.remaining_accounts) {
+            require_keys_eq!(escrow.key(), escrow_key, CustomError::InvalidEscrowAccount);
+            // Token Metadata closes emptied pNFT escrows itself
+            if escrow.owner == &system_program::ID {
+                continue;
+            }
+            let token_program = if escrow.owner == &crate::ID {
+                Account::<SolEscrow>::try_from(escrow)?.close(rent_destination)?;
+                continue;
+            } else if escrow.owner == &token::ID {
+                ctx.accounts.token_program.to_account_info()
+            } else {
+                ctx.accounts.token_2022_program.to_account_info()
+            };
+            token_interface::close_account(CpiContext::new_with_signer(
+                token_program,
+                token_interface::CloseAccount {
+                    account: escrow.clone(),
+                    destination: rent_destination,
+                    authority: challenge.to_account_info(),
+                },
+                signer,
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Hand out an NFT escrowed by a challenge created before bundle stakes,
+     * whose account no longer deserializes as a `Challenge`. The winner of a
+     * completed challenge claims both NFTs. In any other state the challenge
+     * can't be resolved anymore, so each player takes their own NFT back.
+     * The escrow's rent goes back to the depositor, and the challenge is
+     * closed to p1 once no NFT is left in escrow.
+     */
+    pub fn claim_legacy_nft(ctx: Context<ClaimLegacyNft>) -> Result<()> {
+        require!(
+            !ctx.accounts.config.is_paused(Config::PAUSE_CLAIMS),
+            CustomError::Paused
+        );
+        let challenge_info = ctx.accounts.challenge.to_account_info();
+        let mut legacy = LegacyChallenge::try_from_data(&challenge_info.try_borrow_data()?)?;
+        let signer_seeds = ChallengeSignerSeeds {
+            p1: legacy.p1,
+            challenge_id: legacy.challenge_id.to_le_bytes(),
+            bump: [legacy.bump],
+        };
+        let challenge_seeds = signer_seeds.as_slices();
+        require_keys_eq!(
+            Pubkey::create_program_address(&challenge_seeds, &crate::ID)
+                .map_err(|_| CustomError::InvalidChallengeState)?,
+            challenge_info.key(),
+            CustomError::InvalidChallengeState
+        );
+        require_keys_eq!(ctx.accounts.p1.key(), legacy.p1, CustomError::InvalidPayer);
+
+        let claimer = ctx.accounts.claimer.key();
+        let completed = legacy.status == LegacyChallenge::COMPLETED;
+        let winner = legacy.winner;
+        let nft_mint = ctx.accounts.nft_mint.key();
+        let (depositor, status) = if nft_mint == legacy.nft1_mint {
+            (legacy.p1, &mut legacy.nft1_status)
+        } else if nft_mint == legacy.nft2_mint {
+            (legacy.p2, &mut legacy.nft2_status)
+        } else {
+            return err!(CustomError::InvalidNftMint);
+        };
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        require_keys_eq!(ctx.accounts.depositor.key(), depositor, CustomError::InvalidPayer);
+        *status = if completed {
+            require!(Some(claimer) == winner, CustomError::InvalidPayer);
+            NFTStatus::Claimed
+        } else {
+            require_keys_eq!(claimer, depositor, CustomError::InvalidPayer);
+            NFTStatus::Refunded
+        };
+        legacy.write_nft_statuses(&mut challenge_info.try_borrow_mut_data()?);
+
+        let signer = &[&challenge_seeds[..]];
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::TransferChecked {
+                    from: ctx.accounts.escrow_nft_account.to_account_info(),
+                    mint: ctx.accounts.nft_mint.to_account_info(),
+                    to: ctx.accounts.destination_token_account.to_account_info(),
+                    authority: challenge_info.clone(),
+                },
+                signer,
+            ),
+            1,
+            0,
+        )?;
+        token_interface::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token_interface::CloseAccount {
+                account: ctx.accounts.escrow_nft_account.to_account_info(),
+                destination: ctx.accounts.depositor.to_account_info(),
+                authority: challenge_info.clone(),
+            },
+            signer,
+        ))?;
+
+        if legacy.nft1_status != NFTStatus::Deposited && legacy.nft2_status != NFTStatus::Deposited {
+            let rent = challenge_info.lamports();
+            challenge_info.sub_lamports(rent)?;
+            ctx.accounts.p1.add_lamports(rent)?;
+            challenge_info.assign(&system_program::ID);
+            challenge_info.realloc(0, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect a player's service fee, shared by `pay_service_fee` and `accept_challenge`
+/// The fee is held on the challenge PDA until the challenge is settled
+fn pay_fee<'info>(
+    challenge: &mut Account<'info, Challenge>,
+    payer: &Signer<'info>,
+) -> Result<()> {
+    require!(
+        challenge.status == ChallengeStatus::PendingFee,
+        CustomError::InvalidChallengeState
+    );
+    require!(
+        Clock::get()?.unix_timestamp <= challenge.deadlines.fee,
+        CustomError::DeadlinePassed
+    );
+
+    if payer.key() == challenge.p1 {
+        require!(!challenge.p1_paid, CustomError::FeeAlreadyPaid);
+        // If the fee is 0, we don't need to transfer anything and it should be marked as already paid
+        if challenge.p1_fee > 0 {
+            let ix = anchor_lang::solana_program::system_instruction::transfer(
+                payer.key,
+                &challenge.key(),
+                challenge.p1_fee,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &ix,
+                &[payer.to_account_info(), challenge.to_account_info()],
+            )?;
+        }
+        challenge.p1_paid = true;
+    } else if payer.key() == challenge.p2 {
+        require!(!challenge.p2_paid, CustomError::FeeAlreadyPaid);
+        // If the fee is 0, we don't need to transfer anything and it should be marked as already paid
+        if challenge.p2_fee > 0 {
+            let ix = anchor_lang::solana_program::system_instruction::transfer(
+                payer.key,
+                &challenge.key(),
+                challenge.p2_fee,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &ix,
+                &[payer.to_account_info(), challenge.to_account_info()],
+            )?;
+        }
+        challenge.p2_paid = true;
+    } else {
+        return err!(CustomError::InvalidPayer);
+    }
+
+    challenge.refresh_status();
+
+    Ok(())
+}
+
+/// Owned parts of a challenge PDA's seeds, borrowed with `as_slices` to sign CPIs
+struct ChallengeSignerSeeds {
+    p1: Pubkey,
+    challenge_id: [u8; 8],
+    bump: [u8; 1],
+}
+
+impl ChallengeSignerSeeds {
+    fn as_slices(&self) -> [&[u8]; 4] {
+        [b"challenge", self.p1.as_ref(), &self.challenge_id, &self.bump]
+    }
+}
+
+/// The seeds the challenge PDA signs with, `[b"challenge", p1, challenge_id, bump]`
+fn challenge_signer_seeds(challenge: &Challenge) -> ChallengeSignerSeeds {
+    ChallengeSignerSeeds {
+        p1: challenge.p1,
+        challenge_id: challenge.challenge_id.to_le_bytes(),
+        bump: [challenge.bump],
+    }
+}
+
+/// Deposits are only accepted while the challenge is pending and before the escrow deadline
+fn check_deposit_window(challenge: &Challenge) -> Result<()> {
+    require!(challenge.is_pending(), CustomError::InvalidChallengeState);
+    require!(
+        Clock::get()?.unix_timestamp <= challenge.deadlines.escrow,
+        CustomError::DeadlinePassed
+    );
+    Ok(())
+}
+
+/// Validate a new challenge's parameters and write them, shared by both initialize paths
+/// The caller has already checked the API's authorisation
+fn create_challenge(
+    challenge: &mut Account<Challenge>,
+    bump: u8,
+    config: &Config,
+    p1: Pubkey,
+    p2: Pubkey,
+    params: ChallengeParams,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    let ChallengeParams {
+        challenge_id,
+        p1_fee,
+        p2_fee,
+        p1_stake,
+        p2_stake,
+        deadlines,
+        match_rule,
+    } = params;
+    check_new_challenge(config, &deadlines)?;
+    check_stake_terms(config, &p1_stake, &p2_stake, &match_rule, remaining_accounts)?;
+
+    challenge.p1 = p1;
+    challenge.p2 = p2;
+    challenge.challenge_id = challenge_id;
+    challenge.p1_fee = p1_fee;
+    challenge.p2_fee = p2_fee;
+    challenge.p1_paid = p1_fee == 0; // Mark as paid if fee is 0
+    challenge.p2_paid = p2_fee == 0; // Mark as paid if fee is 0
+    challenge.p1_stake = p1_stake.into();
+    challenge.p2_stake = p2_stake.into();
+    challenge.bump = bump;
+    challenge.rent_payer = p1;
+    challenge.status = ChallengeStatus::Invited;
+    challenge.winner = None;
+    challenge.resolution_threshold = config.resolution_threshold;
+    challenge.refund_fees_on_draw = config.refund_fees_on_draw;
+    challenge.deadlines = deadlines;
+    challenge.match_rule = match_rule;
+    challenge.join_constraints = None;
+    challenge.proposed_change = None;
+
+    Ok(())
+}
+
+/// Both stakes are valid and satisfy the allowlist and `match_rule`
+/// `remaining_accounts` are the bundles' metadata or Core asset accounts, as for `bundle_collections`
+fn check_stake_terms(
+    config: &Config,
+    p1_stake: &StakeTerms,
+    p2_stake: &StakeTerms,
+    match_rule: &MatchRule,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    StakeTerms::validate_stakes(&[p1_stake, p2_stake])?;
+    let same_collection = *match_rule == MatchRule::SameCollection;
+    if config.enforce_collection_allowlist || same_collection {
+        let collections = bundle_collections(
+            &[p1_stake, p2_stake],
+            remaining_accounts,
+            config.enforce_collection_allowlist,
+        )?;
+        if same_collection {
+            require!(
+                p1_stake.has_collection() && p2_stake.has_collection(),
+                CustomError::MatchRuleNotMet
+            );
+            require!(
+                collections.windows(2).all(|pair| pair[0] == pair[1]),
+                CustomError::MatchRuleNotMet
+            );
+        }
+    }
+    if let MatchRule::ValueTier { p1_tier, p2_tier, .. } = match_rule {
+        require!(p1_tier == p2_tier, CustomError::MatchRuleNotMet);
+    }
+    Ok(())
+}
+
+/// A player's stake under new terms, returning escrowed NFTs the new terms drop
+/// NFTs kept by the new terms stay deposited. `returns` yields an
+/// (escrow, player token account, mint) triple for each returned NFT
+fn restake<'info>(
+    challenge: &Account<'info, Challenge>,
+    player: &AccountInfo<'info>,
+    old: &Stake,
+    new: StakeTerms,
+    returns: &mut &'info [AccountInfo<'info>],
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<Stake> {
+    if old.terms() == new {
+        return Ok(old.clone());
+    }
+    // A deposit may have landed since the change was proposed
+    require!(!old.drops_unreturnable(&new), CustomError::UnreturnableDeposit);
+    let old_nfts = match old {
+        Stake::Nft { nfts } => nfts.clone(),
+        Stake::Token { mint, status, escrowed, .. } if *status == NFTStatus::Deposited => {
+            let accounts = next_accounts(returns, 3)?;
+            require_keys_eq!(accounts[2].key(), *mint, CustomError::InvalidStakeMint);
+            return_tokens(challenge, player, accounts, *escrowed, token_program)?;
+            vec![]
+        }
+        Stake::Sol { status, .. } if *status == NFTStatus::Deposited => {
+            return_sol(&challenge.key(), player, &next_accounts(returns, 1)?[0])?;
+            vec![]
+        }
+        _ => vec![],
+    };
+    let new_mints = match &new {
+        StakeTerms::Nft { mints } => mints.clone(),
+        _ => vec![],
+    };
+
+    for nft in old_nfts
+        .iter()
+        .filter(|nft| nft.status.is_held() && !new_mints.contains(&nft.mint))
+    {
+        let accounts = next_accounts(returns, 3)?;
+        require_keys_eq!(accounts[2].key(), nft.mint, CustomError::InvalidNftMint);
+        return_nft(challenge, player, accounts, token_program)?;
+    }
+
+    Ok(match new {
+        StakeTerms::Nft { mints } => Stake::Nft {
+            nfts: mints
+                .into_iter()
+                .map(|mint| {
+                    old_nfts
+                        .iter()
+                        .find(|nft| nft.mint == mint && nft.status.is_held())
+                        .cloned()
+                        .unwrap_or_else(|| StakedNft::new(mint))
+                })
+                .collect(),
+        },
+        terms => terms.into(),
+    })
+}
+
+/// Give an escrowed NFT back to its depositor before the challenge settles
+/// `accounts` is an (escrow, player token account, mint) triple. The escrow is
+/// closed to the player since `close_challenge` will no longer expect it
+fn return_nft<'info>(
+    challenge: &Account<'info, Challenge>,
+    player: &AccountInfo<'info>,
+    accounts: &[AccountInfo<'info>],
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    let (escrow, destination, nft_mint) = (&accounts[0], &accounts[1], &accounts[2]);
+    let destination_account = TokenAccount::try_deserialize(&mut &destination.try_borrow_data()?[..])?;
+    require_keys_eq!(destination_account.owner, player.key(), CustomError::InvalidPayer);
+
+    release_nft(challenge, escrow, destination, nft_mint, token_program)?;
+    close_escrow(challenge, player, escrow, token_program)
+}
+
+/// Return `amount` of a token stake to `player` and close its escrow, refunding the rent
+/// `accounts` is the (escrow, player token account, mint) triple
+fn return_tokens<'info>(
+    challenge: &Account<'info, Challenge>,
+    player: &AccountInfo<'info>,
+    accounts: &[AccountInfo<'info>],
+    amount: u64,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    let (escrow, destination, mint) = (&accounts[0], &accounts[1], &accounts[2]);
+    require_keys_eq!(
+        escrow.key(),
+        token_escrow_address(&challenge.key(), &player.key()),
+        CustomError::InvalidEscrowAccount
+    );
+    let destination_account = TokenAccount::try_deserialize(&mut &destination.try_borrow_data()?[..])?;
+    require_keys_eq!(destination_account.owner, player.key(), CustomError::InvalidPayer);
+    let decimals = Mint::try_deserialize(&mut &mint.try_borrow_data()?[..])?.decimals;
+
+    release_tokens(challenge, escrow, destination, mint, token_program, amount, decimals)?;
+    close_escrow(challenge, player, escrow, token_program)
+}
+
+/// Return a SOL stake to `player` by closing its `SolEscrow`, which also refunds the rent
+fn return_sol<'info>(
+    challenge: &Pubkey,
+    player: &AccountInfo<'info>,
+    escrow: &'info AccountInfo<'info>,
+) -> Result<()> {
+    require_keys_eq!(
+        escrow.key(),
+        sol_escrow_address(challenge, &player.key()),
+        CustomError::InvalidEscrowAccount
+    );
+    Account::<SolEscrow>::try_from(escrow)?.close(player.clone())
+}
+
+/// Close an emptied escrow token account, its rent goes to `player` who paid it
+fn close_escrow<'info>(
+    challenge: &Account<'info, Challenge>,
+    player: &AccountInfo<'info>,
+    escrow: &AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    let signer_seeds = challenge_signer_seeds(challenge);
+    let challenge_seeds = signer_seeds.as_slices();
+    token_interface::close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token_interface::CloseAccount {
+            account: escrow.clone(),
+            destination: player.clone(),
+            authority: challenge.to_account_info(),
+        },
+        &[&challenge_seeds[..]],
+    ))
+}
+
+/// Return the next deposit in `remaining` to whichever of `players` staked it, recording it `returned_status`
+/// Token and SOL stakes are recognised by their escrow address, anything else is an NFT:
+/// - NFTs and token stakes are (escrow, player token account, mint) triples
+/// - SOL stakes are the `SolEscrow` account alone
+fn return_deposit<'info>(
+    challenge: &mut Account<'info, Challenge>,
+    players: &[&AccountInfo<'info>],
+    remaining: &mut &'info [AccountInfo<'info>],
+    returned_status: NFTStatus,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    let challenge_key = challenge.key();
+    let escrow = remaining.first().ok_or(CustomError::InvalidRemainingAccounts)?;
+
+    if let Some(player) = players
+        .iter()
+        .find(|player| escrow.key() == sol_escrow_address(&challenge_key, &player.key()))
+    {
+        let Some(Stake::Sol { status, .. }) = challenge.stake_mut(&player.key()) else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = returned_status;
+        *remaining = &remaining[1..];
+
+        return return_sol(&challenge_key, player, escrow);
+    }
+
+    let accounts = next_accounts(remaining, 3)?;
+    if let Some(player) = players
+        .iter()
+        .find(|player| escrow.key() == token_escrow_address(&challenge_key, &player.key()))
+    {
+        let Some(Stake::Token { mint, status, escrowed, .. }) = challenge.stake_mut(&player.key())
+        else {
+            return err!(CustomError::InvalidStakeType);
+        };
+        require_keys_eq!(*mint, accounts[2].key(), CustomError::InvalidStakeMint);
+        require!(*status == NFTStatus::Deposited, CustomError::InvalidChallengeState);
+        *status = returned_status;
+        let amount = std::mem::take(escrowed);
+
+        return return_tokens(challenge, player, accounts, amount, token_program);
+    }
+
+    let nft_mint = accounts[2].key();
+    let player = players
+        .iter()
+        .find(|player| {
+            matches!(
+                challenge.stake_mut(&player.key()),
+                Some(Stake::Nft { nfts }) if nfts.iter().any(|nft| nft.mint == nft_mint)
+            )
+        })
+        .ok_or(CustomError::InvalidNftMint)?;
+    let staked_nft = challenge
+        .nfts_mut(&player.key())?
+        .iter_mut()
+        .find(|nft| nft.mint == nft_mint)
+        .ok_or(CustomError::InvalidNftMint)?;
+    require!(
+        staked_nft.status == NFTStatus::Deposited,
+        CustomError::InvalidChallengeState
+    );
+    require!(!staked_nft.programmable, CustomError::ProgrammableNftTransfer);
+    staked_nft.status = returned_status;
+
+    return_nft(challenge, player, accounts, token_program)
+}
+
+/// Split the next `count` accounts off the front of `accounts`
+fn next_accounts<'a, 'info>(
+    accounts: &mut &'a [AccountInfo<'info>],
+    count: usize,
+) -> Result<&'a [AccountInfo<'info>]> {
+    require!(accounts.len() >= count, CustomError::InvalidRemainingAccounts);
+    let (next, rest) = accounts.split_at(count);
+    *accounts = rest;
+    Ok(next)
+}
+
+/// Checks shared by every way of creating a challenge
+fn check_new_challenge(config: &Config, deadlines: &ChallengeDeadlines) -> Result<()> {
+    require!(!config.is_paused(Config::PAUSE_CREATION), CustomError::Paused);
+    require!(
+        config.resolution_threshold > 0
+            && config.resolution_threshold as usize <= config.moderator_signers.len(),
+        CustomError::InvalidResolutionThreshold
+    );
+    let now = Clock::get()?.unix_timestamp;
+    require!(
+        now < deadlines.fee
+            && deadlines.fee <= deadlines.escrow
+            && deadlines.escrow <= deadlines.resolution,
+        CustomError::InvalidDeadlines
+    );
+    Ok(())
+}
+
+/// The digest the API signs to authorise a challenge off-chain
+/// Domain-separated by cluster and program so it can't be used on another deployment
+fn challenge_attestation_digest(
+    chain_id: &[u8; 16],
+    p1: &Pubkey,
+    p2: &Pubkey,
+    params: &ChallengeParams,
+    authorization: &ApiAuthorization,
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"trinity:challenge_attestation");
+    hasher.update(chain_id);
+    hasher.update(crate::ID.as_ref());
+    hasher.update(p1.as_ref());
+    hasher.update(p2.as_ref());
+    hasher.update(params.try_to_vec()?);
+    hasher.update(authorization.try_to_vec()?);
+    Ok(hasher.finalize().into())
+}
+
+/// Accept an API authorization once, and only before it expires
+/// Creates the signer's nonce PDA, which fails if the nonce was already used
+fn consume_api_nonce<'info>(
+    api_nonce: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    signer: &Pubkey,
+    authorization: &ApiAuthorization,
+) -> Result<()> {
+    require!(
+        Clock::get()?.unix_timestamp <= authorization.expires_at,
+        CustomError::ApiAuthorizationExpired
+    );
+    let nonce_bytes = authorization.nonce.to_le_bytes();
+    let (nonce_key, nonce_bump) =
+        Pubkey::find_program_address(&[b"api_nonce", signer.as_ref(), &nonce_bytes], &crate::ID);
+    require_keys_eq!(api_nonce.key(), nonce_key, CustomError::InvalidNonceAccount);
+    require!(api_nonce.owner != &crate::ID, CustomError::NonceAlreadyUsed);
+
+    let nonce_seeds: &[&[u8]] = &[b"api_nonce", signer.as_ref(), &nonce_bytes, &[nonce_bump]];
+    create_pda_account(
+        api_nonce,
+        nonce_seeds,
+        8 + UsedNonce::LEN,
+        &crate::ID,
+        payer,
+        system_program,
+    )?;
+
+    let used_nonce = UsedNonce {
+        expires_at: authorization.expires_at,
+        rent_payer: payer.key(),
+    };
+    used_nonce.try_serialize(&mut &mut api_nonce.try_borrow_mut_data()?[..])?;
+
+    Ok(())
+}
+
+/// The verified Metaplex collection of an NFT, read from its metadata account
+fn verified_collection(metadata: &AccountInfo, mint: &Pubkey) -> Result<Pubkey> {
+    require_keys_eq!(*metadata.owner, TokenMetadata::id(), CustomError::InvalidMetadataAccount);
+    let metadata = MetadataAccount::try_deserialize(&mut &metadata.try_borrow_data()?[..])?;
+    require_keys_eq!(metadata.mint, *mint, CustomError::InvalidMetadataAccount);
+    match &metadata.collection {
+        Some(collection) if collection.verified => Ok(collection.key),
+        _ => err!(CustomError::CollectionNotVerified),
+    }
+}
+
+/// Require a deposited NFT's verified collection to be on the allowlist
+fn check_nft_allowlisted(
+    metadata: Option<&AccountInfo>,
+    allowed_collection: Option<&AccountInfo>,
+    mint: &Pubkey,
+) -> Result<()> {
+    let metadata = metadata.ok_or(CustomError::InvalidMetadataAccount)?;
+    let collection = verified_collection(metadata, mint)?;
+    let allowed_collection = allowed_collection.ok_or(CustomError::CollectionNotAllowed)?;
+    allowlist_entry(allowed_collection, &collection)?;
+    Ok(())
+}
+
+/// The collection a Core asset belongs to, if any
+fn core_collection(asset: &AccountInfo) -> Result<Option<Pubkey>> {
+    Ok(match BaseAssetV1::try_from(asset)?.update_authority {
+        UpdateAuthority::Collection(address) => Some(address),
+        _ => None,
+    })
+}
+
+/// Deserialize `collection`'s allowlist entry, failing if the account isn't one
+fn allowlist_entry(account: &AccountInfo, collection: &Pubkey) -> Result<AllowedCollection> {
+    require_keys_eq!(*account.owner, crate::ID, CustomError::CollectionNotAllowed);
+    let allowed = AllowedCollection::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+    require_keys_eq!(allowed.collection, *collection, CustomError::CollectionNotAllowed);
+    Ok(allowed)
+}
+
+/// The verified collection of every NFT and Core asset in the given bundles, in order
+/// `accounts` holds each NFT's metadata or Core asset account, followed by its allowlist entry
+/// when `allowlist` is set. While `allowlist` is set cNFT stakes are rejected since their
+/// collection can't be read without the leaf's proof
+fn bundle_collections(
+    stakes: &[&StakeTerms],
+    accounts: &[AccountInfo],
+    allowlist: bool,
+) -> Result<Vec<Pubkey>> {
+    let stride = if allowlist { 2 } else { 1 };
+    let mut entries = accounts.chunks_exact(stride);
+    require!(
+        entries.remainder().is_empty(),
+        CustomError::InvalidRemainingAccounts
+    );
+    let mut collections = vec![];
+    for terms in stakes {
+        let mints = match terms {
+            StakeTerms::Nft { mints } => mints,
+            StakeTerms::Core { asset } => {
+                let entry = entries.next().ok_or(CustomError::InvalidRemainingAccounts)?;
+                require_keys_eq!(entry[0].key(), *asset, CustomError::InvalidCoreAsset);
+                require_keys_eq!(*entry[0].owner, mpl_core::ID, CustomError::InvalidCoreAsset);
+                let Some(collection) = core_collection(&entry[0])? else {
+                    return match allowlist {
+                        true => err!(CustomError::CollectionNotAllowed),
+                        false => err!(CustomError::MatchRuleNotMet),
+                    };
+                };
+                if allowlist {
+                    allowlist_entry(&entry[1], &collection)?;
+                }
+                collections.push(collection);
+                continue;
+            }
+            StakeTerms::Cnft { .. } if allowlist => return err!(CustomError::CnftNotAllowlistable),
+            _ => continue,
+        };
+        let mut per_collection: Vec<(Pubkey, u8)> = vec![];
+        for mint in mints {
+            let entry = entries.next().ok_or(CustomError::InvalidRemainingAccounts)?;
+            let collection = verified_collection(&entry[0], mint)?;
+            collections.push(collection);
+            if !allowlist {
+                continue;
+            }
+
+            let allowed = allowlist_entry(&entry[1], &collection)?;
+
+            let count = match per_collection.iter_mut().find(|(key, _)| *key == collection) {
+                Some((_, count)) => {
+                    *count += 1;
+                    *count
+                }
+                None => {
+                    per_collection.push((collection, 1));
+                    1
+                }
+            };
+            require!(
+                allowed.max_nfts_per_stake == 0 || count <= allowed.max_nfts_per_stake,
+                CustomError::CollectionLimitExceeded
+            );
+        }
+    }
+    require!(entries.next().is_none(), CustomError::InvalidRemainingAccounts);
+
+    Ok(collections)
+}
+
+/// The message the API signs to attest the value tier of both stakes
+/// Binds the attestation to this cluster, program, challenge and stakes
+fn price_attestation_message(
+    chain_id: &[u8; 16],
+    challenge: &Pubkey,
+    p1_stake: &StakeTerms,
+    p2_stake: &StakeTerms,
+    match_rule: &MatchRule,
+    authorization: &ApiAuthorization,
+) -> Result<Vec<u8>> {
+    let mut message = b"trinity:price_attestation".to_vec();
+    message.extend_from_slice(chain_id);
+    message.extend_from_slice(crate::ID.as_ref());
+    message.extend_from_slice(challenge.as_ref());
+    p1_stake.serialize(&mut message)?;
+    p2_stake.serialize(&mut message)?;
+    match_rule.serialize(&mut message)?;
+    authorization.serialize(&mut message)?;
+    Ok(message)
+}
+
+/// Check that the instruction before this one is an Ed25519 program
+/// verification of `message` by one of the config's API signers
+fn verify_api_signature(
+    sysvar_instructions: &AccountInfo,
+    config: &Config,
+    message: &[u8],
+) -> Result<Pubkey> {
+    let current = instructions_sysvar::load_current_index_checked(sysvar_instructions)?;
+    require!(current > 0, CustomError::InvalidApiSignature);
+    let ix = instructions_sysvar::load_instruction_at_checked(
+        usize::from(current - 1),
+        sysvar_instructions,
+    )?;
+    require_keys_eq!(ix.program_id, ed25519_program::ID, CustomError::InvalidApiSignature);
+
+    // A single signature whose offsets all point into the Ed25519 instruction itself
+    let data = &ix.data;
+    require!(data.len() >= 16 && data[0] == 1, CustomError::InvalidApiSignature);
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let public_key_offset = usize::from(read_u16(6));
+    let message_offset = usize::from(read_u16(10));
+    let message_size = usize::from(read_u16(12));
+    require!(
+        [read_u16(4), read_u16(8), read_u16(14)]
+            .iter()
+            .all(|index| *index == u16::MAX),
+        CustomError::InvalidApiSignature
+    );
+    let signer = data
+        .get(public_key_offset..public_key_offset + 32)
+        .and_then(|key| Pubkey::try_from(key).ok())
+        .ok_or(CustomError::InvalidApiSignature)?;
+    let signed_message = data
+        .get(message_offset..message_offset + message_size)
+        .ok_or(CustomError::InvalidApiSignature)?;
+
+    require!(
+        config.api_signers.contains(&signer),
+        CustomError::UnauthorizedApiSigner
+    );
+    require!(signed_message == message, CustomError::InvalidApiSignature);
+
+    Ok(signer)
+}
+
+/// Move one of a player's declared NFTs into escrow, shared by `deposit_nft` and `accept_challenge`
+fn escrow_nft<'info>(
+    challenge: &mut Account<'info, Challenge>,
+    depositor: &Signer<'info>,
+    from_token_account: &InterfaceAccount<'info, TokenAccount>,
+    escrow_token_account: &AccountInfo<'info>,
+    nft_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    mark_nft_deposited(challenge, depositor, from_token_account, nft_mint)?;
+
+    let cpi_accounts = token_interface::TransferChecked {
+        from: from_token_account.to_account_info(),
+        mint: nft_mint.to_account_info(),
+        to: escrow_token_account.clone(),
+        authority: depositor.to_account_info(),
+    };
+    token_interface::transfer_checked(
+        CpiContext::new(token_program.to_account_info(), cpi_accounts),
+        1,
+        0,
+    )?;
+
+    challenge.refresh_status();
+
+    Ok(())
+}
+
+/// Check a deposit of one of the depositor's declared NFTs and record it as escrowed
+fn mark_nft_deposited<'a, 'info>(
+    challenge: &'a mut Account<'info, Challenge>,
+    depositor: &Signer<'info>,
+    from_token_account: &TokenAccount,
+    nft_mint: &InterfaceAccount<'info, Mint>,
+) -> Result<&'a mut StakedNft> {
+    check_deposit_window(challenge)?;
+
+    let staked_nft = challenge
+        .nfts_mut(&depositor.key())?
+        .iter_mut()
+        .find(|nft| nft.mint == nft_mint.key())
+        .ok_or(CustomError::InvalidNftMint)?;
+    require!(
+        staked_nft.status == NFTStatus::NotDeposited,
+        CustomError::AlreadyDeposited
+    );
+    require!(nft_mint.decimals == 0, CustomError::InvalidNftDecimals);
+    require!(nft_mint.supply == 1, CustomError::InvalidNftSupply);
+    require_keys_eq!(
+        from_token_account.mint,
+        nft_mint.key(),
+        CustomError::SourceMintMismatch
+    );
+    require_keys_eq!(
+        from_token_account.owner,
+        depositor.key(),
+        CustomError::SourceOwnerMismatch
+    );
+    require!(from_token_account.amount == 1, CustomError::SourceAccountEmpty);
+    check_mint_extensions(nft_mint)?;
+    // Any non-zero fee on a single token withholds the whole NFT
+    require!(!has_transfer_fee(nft_mint)?, CustomError::TransferFeeNft);
+    staked_nft.status = NFTStatus::Deposited;
+
+    Ok(staked_nft)
+}
+
+/// Move a pNFT between the player's token account and the challenge escrow through Token Metadata
+/// The challenge PDA signs when the pNFT leaves escrow
+fn transfer_pnft(accounts: &PnftEscrow, into_escrow: bool) -> Result<()> {
+    let challenge = &accounts.challenge;
+    let player = accounts.player.to_account_info();
+    let challenge_info = challenge.to_account_info();
+    let player_token_account = accounts.player_token_account.to_account_info();
+    let escrow_token_account = accounts.escrow_token_account.to_account_info();
+    let player_token_record = accounts.player_token_record.to_account_info();
+    let escrow_token_record = accounts.escrow_token_record.to_account_info();
+    let (source, source_record, owner, destination, destination_record, destination_owner) =
+        if into_escrow {
+            (
+                &player_token_account,
+                &player_token_record,
+                &player,
+                &escrow_token_account,
+                &escrow_token_record,
+                &challenge_info,
+            )
+        } else {
+            (
+                &escrow_token_account,
+                &escrow_token_record,
+                &challenge_info,
+                &player_token_account,
+                &player_token_record,
+                &player,
+            )
+        };
+
+    let token_metadata_program = accounts.token_metadata_program.to_account_info();
+    let nft_mint = accounts.nft_mint.to_account_info();
+    let metadata = accounts.metadata.to_account_info();
+    let edition = accounts.edition.to_account_info();
+    let system_program = accounts.system_program.to_account_info();
+    let sysvar_instructions = accounts.sysvar_instructions.to_account_info();
+    let token_program = accounts.token_program.to_account_info();
+    let associated_token_program = accounts.associated_token_program.to_account_info();
+    let authorization_rules_program = accounts
+        .authorization_rules_program
+        .as_ref()
+        .map(|program| program.to_account_info());
+    let authorization_rules = accounts
+        .authorization_rules
+        .as_ref()
+        .map(|rules| rules.to_account_info());
+
+    let mut transfer = TransferV1CpiBuilder::new(&token_metadata_program);
+    transfer
+        .token(source)
+        .token_owner(owner)
+        .destination_token(destination)
+        .destination_owner(destination_owner)
+        .mint(&nft_mint)
+        .metadata(&metadata)
+        .edition(Some(&edition))
+        .token_record(Some(source_record))
+        .destination_token_record(Some(destination_record))
+        .authority(owner)
+        .payer(&player)
+        .system_program(&system_program)
+        .sysvar_instructions(&sysvar_instructions)
+        .spl_token_program(&token_program)
+        .spl_ata_program(&associated_token_program)
+        .authorization_rules_program(authorization_rules_program.as_ref())
+        .authorization_rules(authorization_rules.as_ref())
+        .amount(1);
+
+    if into_escrow {
+        transfer.invoke()?;
+    } else {
+        let signer_seeds = challenge_signer_seeds(challenge);
+        let challenge_seeds = signer_seeds.as_slices();
+        transfer.invoke_signed(&[&challenge_seeds[..]])?;
+    }
+
+    Ok(())
+}
+
+/// Freeze or thaw a locked NFT's token account through Token Metadata, the challenge PDA signs as delegate
+fn set_nft_frozen(accounts: &LockNft, frozen: bool) -> Result<()> {
+    let challenge = &accounts.challenge;
+    let signer_seeds = challenge_signer_seeds(challenge);
+    let challenge_seeds = signer_seeds.as_slices();
+    let token_metadata_program = accounts.token_metadata_program.to_account_info();
+    let challenge_info = challenge.to_account_info();
+    let player_token_account = accounts.player_token_account.to_account_info();
+    let edition = accounts.edition.to_account_info();
+    let nft_mint = accounts.nft_mint.to_account_info();
+    let token_program = accounts.token_program.to_account_info();
+
+    if frozen {
+        FreezeDelegatedAccountCpiBuilder::new(&token_metadata_program)
+            .delegate(&challenge_info)
+            .token_account(&player_token_account)
+            .edition(&edition)
+            .mint(&nft_mint)
+            .token_program(&token_program)
+            .invoke_signed(&[&challenge_seeds[..]])?;
+    } else {
+        ThawDelegatedAccountCpiBuilder::new(&token_metadata_program)
+            .delegate(&challenge_info)
+            .token_account(&player_token_account)
+            .edition(&edition)
+            .mint(&nft_mint)
+            .token_program(&token_program)
+            .invoke_signed(&[&challenge_seeds[..]])?;
+    }
+
+    Ok(())
+}
+
+/// Move a Core asset between the player and the challenge PDA through mpl-core
+/// The challenge PDA signs when the asset leaves escrow
+fn transfer_core(accounts: &CoreEscrow, into_escrow: bool) -> Result<()> {
+    let challenge = &accounts.challenge;
+    let player = accounts.player.to_account_info();
+    let challenge_info = challenge.to_account_info();
+    let (owner, new_owner) = if into_escrow {
+        (&player, &challenge_info)
+    } else {
+        (&challenge_info, &player)
+    };
+
+    let core_program = accounts.core_program.to_account_info();
+    let asset = accounts.asset.to_account_info();
+    let collection = accounts
+        .collection
+        .as_ref()
+        .map(|collection| collection.to_account_info());
+    let system_program = accounts.system_program.to_account_info();
+
+    let mut transfer = CoreTransferV1CpiBuilder::new(&core_program);
+    transfer
+        .asset(&asset)
+        .collection(collection.as_ref())
+        .payer(&player)
+        .authority(Some(owner))
+        .new_owner(new_owner)
+        .system_program(Some(&system_program));
+
+    if into_escrow {
+        transfer.invoke()?;
+    } else {
+        let signer_seeds = challenge_signer_seeds(challenge);
+        let challenge_seeds = signer_seeds.as_slices();
+        transfer.invoke_signed(&[&challenge_seeds[..]])?;
+    }
+
+    Ok(())
+}
+
+/// Move a cNFT leaf between the player and the challenge PDA through Bubblegum
+/// The challenge PDA signs as leaf owner and delegate when the leaf leaves escrow
+fn transfer_cnft<'info>(
+    accounts: &CnftEscrow<'info>,
+    proof: &'info [AccountInfo<'info>],
+    leaf: &CnftLeaf,
+    into_escrow: bool,
+) -> Result<()> {
+    let challenge = &accounts.challenge;
+    let player = accounts.player.to_account_info();
+    let challenge_info = challenge.to_account_info();
+    let (owner, new_owner) = if into_escrow {
+        (&player, &challenge_info)
+    } else {
+        (&challenge_info, &player)
+    };
+
+    let bubblegum_program = accounts.bubblegum_program.to_account_info();
+    let tree_config = accounts.tree_config.to_account_info();
+    let merkle_tree = accounts.merkle_tree.to_account_info();
+    let log_wrapper = accounts.log_wrapper.to_account_info();
+    let compression_program = accounts.compression_program.to_account_info();
+    let system_program = accounts.system_program.to_account_info();
+    let proof = proof
+        .iter()
+        .map(|node| (node, false, false))
+        .collect::<Vec<_>>();
+
+    let mut transfer = BubblegumTransferCpiBuilder::new(&bubblegum_program);
+    transfer
+        .tree_config(&tree_config)
+        .leaf_owner(owner, true)
+        .leaf_delegate(owner, false)
+        .new_leaf_owner(new_owner)
+        .merkle_tree(&merkle_tree)
+        .log_wrapper(&log_wrapper)
+        .compression_program(&compression_program)
+        .system_program(&system_program)
+        .root(leaf.root)
+        .data_hash(leaf.data_hash)
+        .creator_hash(leaf.creator_hash)
+        .nonce(leaf.nonce)
+        .index(leaf.index)
+        .add_remaining_accounts(&proof);
+
+    if into_escrow {
+        transfer.invoke()?;
+    } else {
+        let signer_seeds = challenge_signer_seeds(challenge);
+        let challenge_seeds = signer_seeds.as_slices();
+        transfer.invoke_signed(&[&challenge_seeds[..]])?;
+    }
+
+    Ok(())
+}
+
+/// Deposit (source token account, escrow, mint) triples passed as remaining accounts
+/// With `allowlist` each triple is followed by the NFT's metadata and allowlist entry
+fn escrow_remaining_nfts<'info>(
+    challenge: &mut Account<'info, Challenge>,
+    depositor: &Signer<'info>,
+    remaining_accounts: &'info [AccountInfo<'info>],
+    allowlist: bool,
+    token_program: &Interface<'info, TokenInterface>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    let chunk = if allowlist { 5 } else { 3 };
+    require!(
+        remaining_accounts.chunks_exact(chunk).remainder().is_empty(),
+        CustomError::InvalidRemainingAccounts
+    );
+
+    for accounts in remaining_accounts.chunks(chunk) {
+        let from_token_account = InterfaceAccount::<TokenAccount>::try_from(&accounts[0])?;
+        let escrow_token_account = &accounts[1];
+        let nft_mint = InterfaceAccount::<Mint>::try_from(&accounts[2])?;
+        if allowlist {
+            check_nft_allowlisted(Some(&accounts[3]), Some(&accounts[4]), &nft_mint.key())?;
+        }
+
+        init_escrow_if_needed(
+            challenge,
+            escrow_token_account,
+            &nft_mint,
+            depositor,
+            token_program,
+            system_program,
+        )?;
+        escrow_nft(
+            challenge,
+            depositor,
+            &from_token_account,
+            escrow_token_account,
+            &nft_mint,
+            token_program,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The escrow token account PDA holding `mint` for `challenge`
+fn escrow_address(challenge: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"escrow", challenge.as_ref(), mint.as_ref()], &crate::ID)
+}
+
+/// The escrow token account PDA holding `player`'s token stake
+fn token_escrow_address(challenge: &Pubkey, player: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"token_escrow", challenge.as_ref(), player.as_ref()],
+        &crate::ID,
+    )
+    .0
+}
+
+/// The `SolEscrow` PDA holding `player`'s SOL stake
+fn sol_escrow_address(challenge: &Pubkey, player: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"sol_escrow", challenge.as_ref(), player.as_ref()],
+        &crate::ID,
+    )
+    .0
+}
+
+/// Token account size for `mint`, including the extensions Token-2022 requires for it
+fn token_account_space(mint: &AccountInfo) -> Result<usize> {
+    if *mint.owner != Token2022::id() {
+        return Ok(token::TokenAccount::LEN);
+    }
+    let mint_data = mint.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    let required_extensions =
+        ExtensionType::get_required_init_account_extensions(&mint_state.get_extension_types()?);
+    Ok(ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(
+        &required_extensions,
+    )?)
+}
+
+/// Reject Token-2022 mints whose extensions let someone else move, block or keep the escrowed tokens
+fn check_mint_extensions(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != Token2022::id() {
+        return Ok(());
+    }
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    for extension in mint_state.get_extension_types()? {
+        match extension {
+            ExtensionType::TransferHook => return err!(CustomError::TransferHookNotSupported),
+            ExtensionType::PermanentDelegate => {
+                return err!(CustomError::PermanentDelegateNotSupported)
+            }
+            ExtensionType::NonTransferable => return err!(CustomError::NonTransferableMint),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The mint charges a Token-2022 transfer fee, so escrows receive less than was sent
+fn has_transfer_fee(mint: &InterfaceAccount<Mint>) -> Result<bool> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != Token2022::id() {
+        return Ok(false);
+    }
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    Ok(mint_state
+        .get_extension_types()?
+        .contains(&ExtensionType::TransferFeeConfig))
+}
+
+/// Create a rent-exempt PDA of `space` bytes owned by `owner`, signed with its `seeds`
+fn create_pda_account<'info>(
+    account: &AccountInfo<'info>,
+    seeds: &[&[u8]],
+    space: usize,
+    owner: &Pubkey,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    let signer = &[seeds];
+    let rent = Rent::get()?.minimum_balance(space);
+
+    // Someone may have sent lamports to the address already, so don't rely on create_account
+    let shortfall = rent.saturating_sub(account.lamports());
+    if shortfall > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            shortfall,
+        )?;
+    }
+    system_program::allocate(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Allocate {
+                account_to_allocate: account.clone(),
+            },
+            signer,
+        ),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Assign {
+                account_to_assign: account.clone(),
+            },
+            signer,
+        ),
+        owner,
+    )
+}
+
+/// Create the escrow token account PDA, mirroring the `init_if_needed` used by `DepositNft`
+fn init_escrow_if_needed<'info>(
+    challenge: &Account<'info, Challenge>,
+    escrow_token_account: &AccountInfo<'info>,
+    nft_mint: &InterfaceAccount<'info, Mint>,
+    payer: &Signer<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    let challenge_key = challenge.key();
+    let mint_key = nft_mint.key();
+    let (escrow_key, escrow_bump) = escrow_address(&challenge_key, &mint_key);
+    require_keys_eq!(
+        escrow_token_account.key(),
+        escrow_key,
+        CustomError::InvalidEscrowAccount
+    );
+    if escrow_token_account.owner == token_program.key {
+        return Ok(());
+    }
+
+    let escrow_seeds: &[&[u8]] = &[
+        b"escrow",
+        challenge_key.as_ref(),
+        mint_key.as_ref(),
+        &[escrow_bump],
+    ];
+    create_pda_account(
+        escrow_token_account,
+        escrow_seeds,
+        token_account_space(&nft_mint.to_account_info())?,
+        token_program.key,
+        payer,
+        system_program,
+    )?;
+    token_interface::initialize_account3(CpiContext::new(
+        token_program.to_account_info(),
+        token_interface::InitializeAccount3 {
+            account: escrow_token_account.clone(),
+            mint: nft_mint.to_account_info(),
+            authority: challenge.to_account_info(),
+        },
+    ))
+}
+
+/// Transfer an escrowed NFT out, signed by the challenge PDA
+fn release_nft<'info>(
+    challenge: &Account<'info, Challenge>,
+    escrow_token_account: &AccountInfo<'info>,
+    destination_token_account: &AccountInfo<'info>,
+    nft_mint: &AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    let (escrow_key, _) = escrow_address(&challenge.key(), &nft_mint.key());
+    require_keys_eq!(
+        escrow_token_account.key(),
+        escrow_key,
+        CustomError::InvalidEscrowAccount
+    );
+    // Deposits only accept 0 decimal mints
+    release_tokens(
+        challenge,
+        escrow_token_account,
+        destination_token_account,
+        nft_mint,
+        token_program,
+        1,
+        0,
+    )
+}
+
+/// Transfer `amount` out of an escrow token account, signed by the challenge PDA
+fn release_tokens<'info>(
+    challenge: &Account<'info, Challenge>,
+    escrow_token_account: &AccountInfo<'info>,
+    destination_token_account: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    amount: u64,
+    decimals: u8,
+) -> Result<()> {
+    let signer_seeds = challenge_signer_seeds(challenge);
+    let challenge_seeds = signer_seeds.as_slices();
+    let signer = &[&challenge_seeds[..]];
+
+    token_interface::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token_interface::TransferChecked {
+                from: escrow_token_account.clone(),
+                mint: mint.clone(),
+                to: destination_token_account.clone(),
+                authority: challenge.to_account_info(),
+            },
+            signer,
+        ),
+        amount,
+        decimals,
+    )
+}
+
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub bump: u8,
+    pub treasury: Pubkey,
+    pub chain_id: [u8; 16], // chain identifier (16 bytes)
+    pub api_signers: Vec<Pubkey>,
+    pub moderator_signers: Vec<Pubkey>,
+    // Fields below were added after the first deployment, new fields go at the end
+    pub pending_admin: Option<Pubkey>,
+    pub resolution_threshold: u8,
+    pub paused: u8, // bitmask of Config::PAUSE_* flags
+    pub refund_fees_on_draw: bool,
+    pub enforce_collection_allowlist: bool,
+    /// Layout version, see `migrate_config`
+    pub version: u8,
+}
+
+impl Config {
+    pub const MAX_SIGNERS: usize = 10;
+    pub const DEFAULT_RESOLUTION_THRESHOLD: u8 = 3;
+    /// Layout written by this program, 0 is the original deployment
+    pub const VERSION: u8 = 1;
+    pub const MAX_SIZE: usize = 32 + 1 + 32 + 16 + (32 * Self::MAX_SIGNERS * 2) + (4 * 2) + 33 + 1 + 1 + 1 + 1 + 1;
+
+    /// Blocks `initialize_challenge`
+    pub const PAUSE_CREATION: u8 = 1 << 0;
+    /// Blocks `pay_service_fee` and stake deposits
+    pub const PAUSE_DEPOSITS: u8 = 1 << 1;
+    /// Blocks `resolve_challenge`
+    pub const PAUSE_RESOLUTION: u8 = 1 << 2;
+    /// Blocks `claim_winnings`, refunds and fee collection
+    pub const PAUSE_CLAIMS: u8 = 1 << 3;
+    pub const PAUSE_ALL: u8 = Self::PAUSE_CREATION
+        | Self::PAUSE_DEPOSITS
+        | Self::PAUSE_RESOLUTION
+        | Self::PAUSE_CLAIMS;
+
+    pub fn is_paused(&self, flags: u8) -> bool {
+        self.paused & flags != 0
+    }
+}
+
+#[account]
+pub struct Challenge {
+    pub p1: Pubkey,
+    pub p2: Pubkey,
+    pub challenge_id: u64,
+    pub p1_fee: u64,
+    pub p2_fee: u64,
+    pub p1_paid: bool,
+    pub p2_paid: bool,
+    pub p1_stake: Stake,
+    pub p2_stake: Stake,
+    pub bump: u8,
+    pub status: ChallengeStatus,
+    pub winner: Option<Pubkey>,
+    pub cancelled: bool,
+    pub moderator_votes: Vec<Pubkey>,
+    pub votes_for_p1: u8,
+    pub votes_for_p2: u8,
+    pub votes_to_cancel: u8,
+    pub resolution_threshold: u8,
+    pub deadlines: ChallengeDeadlines,
+    pub fees_collected: bool,
+    pub p1_fee_refunded: bool,
+    pub p2_fee_refunded: bool,
+    pub rent_payer: Pubkey,
+    pub votes_for_draw: u8,
+    pub drawn: bool,
+    pub refund_fees_on_draw: bool,
+    pub match_rule: MatchRule,
+    /// Set for open challenges, p2 is unset until someone joins
+    pub join_constraints: Option<JoinConstraints>,
+    /// Terms one player proposed, waiting for the other to accept
+    pub proposed_change: Option<ProposedStakeChange>,
+}
+
+impl Challenge {
+    pub const MAX_BUNDLE_SIZE: usize = 5;
+    pub const LEN: usize = 552
+        + 2 * Stake::LEN
+        + MatchRule::LEN
+        + 1
+        + JoinConstraints::LEN
+        + 1
+        + ProposedStakeChange::LEN;
+
+    /// Moderators have reached a final outcome
+    pub fn is_resolved(&self) -> bool {
+        matches!(
+            self.status,
+            ChallengeStatus::Completed | ChallengeStatus::Cancelled | ChallengeStatus::Drawn
+        )
+    }
+
+    /// Held fees are owed to the treasury
+    pub fn fees_collectable(&self) -> bool {
+        match self.status {
+            ChallengeStatus::Completed => true,
+            ChallengeStatus::Drawn => !self.refund_fees_on_draw,
+            _ => false,
+        }
+    }
+
+    /// Held fees are owed back to the players
+    pub fn fees_refundable(&self) -> bool {
+        match self.status {
+            ChallengeStatus::Cancelled => true,
+            ChallengeStatus::Drawn => self.refund_fees_on_draw,
+            _ => false,
+        }
+    }
+
+    pub fn is_player(&self, player: &Pubkey) -> bool {
+        *player == self.p1 || *player == self.p2
+    }
+
+    /// The stake a player declared for this challenge
+    pub fn stake_mut(&mut self, player: &Pubkey) -> Option<&mut Stake> {
+        if *player == self.p1 {
+            Some(&mut self.p1_stake)
+        } else if *player == self.p2 {
+            Some(&mut self.p2_stake)
+        } else {
+            None
+        }
+    }
+
+    /// The NFT bundle a player declared for this challenge
+    pub fn nfts_mut(&mut self, player: &Pubkey) -> Result<&mut Vec<StakedNft>> {
+        match self.stake_mut(player) {
+            Some(Stake::Nft { nfts }) => Ok(nfts),
+            Some(_) => err!(CustomError::InvalidStakeType),
+            None => err!(CustomError::InvalidPayer),
+        }
+    }
+
+    /// Mark one of the claimer's own escrowed NFTs refunded once the challenge is called off
+    /// `claim_refund_nft` then releases it from the `[b"escrow", challenge, mint]` escrow
+    pub fn refund_nft(&mut self, claimer: &Pubkey, mint: &Pubkey) -> Result<()> {
+        require!(
+            matches!(self.status, ChallengeStatus::Cancelled | ChallengeStatus::Drawn),
+            CustomError::InvalidChallengeState
+        );
+        let staked_nft = self
+            .nfts_mut(claimer)?
+            .iter_mut()
+            .find(|nft| nft.mint == *mint)
+            .ok_or(CustomError::InvalidNftMint)?;
+        require!(
+            staked_nft.status == NFTStatus::Deposited,
+            CustomError::InvalidChallengeState
+        );
+        require!(!staked_nft.programmable, CustomError::ProgrammableNftTransfer);
+        staked_nft.status = NFTStatus::Refunded;
+        Ok(())
+    }
+
+    /// A staked NFT from either bundle
+    pub fn staked_nft_mut(&mut self, mint: &Pubkey) -> Option<&mut StakedNft> {
+        [&mut self.p1_stake, &mut self.p2_stake]
+            .into_iter()
+            .filter_map(|stake| match stake {
+                Stake::Nft { nfts } => Some(nfts),
+                _ => None,
+            })
+            .flatten()
+            .find(|nft| nft.mint == *mint)
+    }
+
+    /// The status of a staked cNFT from either side
+    pub fn cnft_status_mut(&mut self, tree: &Pubkey, asset: &Pubkey) -> Option<&mut NFTStatus> {
+        [&mut self.p1_stake, &mut self.p2_stake]
+            .into_iter()
+            .find_map(|stake| match stake {
+                Stake::Cnft { merkle_tree, asset_id, status }
+                    if merkle_tree == tree && asset_id == asset =>
+                {
+                    Some(status)
+                }
+                _ => None,
+            })
+    }
+
+    /// The status of a staked Core asset from either side
+    pub fn core_status_mut(&mut self, core_asset: &Pubkey) -> Option<&mut NFTStatus> {
+        [&mut self.p1_stake, &mut self.p2_stake]
+            .into_iter()
+            .find_map(|stake| match stake {
+                Stake::Core { asset, status, .. } if asset == core_asset => Some(status),
+                _ => None,
+            })
+    }
+
+    /// Stakes and fees can still be renegotiated
+    pub fn is_negotiable(&self) -> bool {
+        self.status == ChallengeStatus::Invited || self.is_pending()
+    }
+
+    /// A player may take their deposit back, the opponent has nothing in escrow yet
+    pub fn can_withdraw(&self, player: &Pubkey) -> bool {
+        let opponent_stake = if *player == self.p1 {
+            &self.p2_stake
+        } else if *player == self.p2 {
+            &self.p1_stake
+        } else {
+            return false;
+        };
+        self.is_pending() && !opponent_stake.holds_deposit()
+    }
+
+    /// Status a player's own deposit takes when it is handed back to them:
+    /// refunded once cancelled or drawn, undeposited again when withdrawn
+    pub fn refund_status(&self, player: &Pubkey) -> Result<NFTStatus> {
+        match self.status {
+            ChallengeStatus::Cancelled | ChallengeStatus::Drawn => Ok(NFTStatus::Refunded),
+            _ if self.can_withdraw(player) => Ok(NFTStatus::NotDeposited),
+            _ => err!(CustomError::InvalidChallengeState),
+        }
+    }
+
+    /// Still collecting fees or deposits
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self.status,
+            ChallengeStatus::PendingFee | ChallengeStatus::PendingEscrow
+        )
+    }
+
+    /// Every stake and fee has left the challenge, so it can be closed
+    pub fn is_settled(&self) -> bool {
+        let stakes_settled = !self.p1_stake.holds_deposit() && !self.p2_stake.holds_deposit();
+        let fees_settled = if self.fees_collectable() {
+            self.fees_collected
+        } else if self.fees_refundable() {
+            (!self.p1_paid || self.p1_fee == 0 || self.p1_fee_refunded)
+                && (!self.p2_paid || self.p2_fee == 0 || self.p2_fee_refunded)
+        } else {
+            false
+        };
+        stakes_settled && fees_settled
+    }
+
+    /// Move a pending challenge forward once its fees and deposits are in
+    pub fn refresh_status(&mut self) {
+        if !self.is_pending() {
+            return;
+        }
+        self.status = if !(self.p1_paid && self.p2_paid) {
+            ChallengeStatus::PendingFee
+        } else if self.p1_stake.is_deposited() && self.p2_stake.is_deposited() {
+            ChallengeStatus::Ready
+        } else {
+            ChallengeStatus::PendingEscrow
+        };
+    }
+}
+
+/// A collection whose NFTs may be staked while the allowlist is enforced
+#[account]
+pub struct AllowedCollection {
+    /// Verified Metaplex collection mint, or Core collection address
+    pub collection: Pubkey,
+    /// Most NFTs of this collection one side can stake, 0 for no cap
+    pub max_nfts_per_stake: u8,
+    pub bump: u8,
+}
+
+impl AllowedCollection {
+    pub const LEN: usize = 32 + 1 + 1;
+}
+
+/// Holds a player's SOL stake, the lamports above rent are the stake
+#[account]
+pub struct SolEscrow {
+    pub bump: u8,
+}
+
+impl SolEscrow {
+    pub const LEN: usize = 1;
+}
+
+/// Unix timestamps after which each stage of a challenge can be expired
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct ChallengeDeadlines {
+    pub fee: i64,
+    pub escrow: i64,
+    pub resolution: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChallengeStatus {
+    Invited,
+    PendingFee,
+    PendingEscrow,
+    Ready,
+    Completed,
+    Cancelled,
+    Drawn,
+    /// Waiting for any eligible player to `join_challenge`
+    Open,
+}
+
+/// The `Challenge` layout written before bundle stakes, up to the winner
+/// Only read by `claim_legacy_nft` to hand out NFTs still escrowed by old challenges
+#[derive(AnchorDeserialize)]
+pub struct LegacyChallenge {
+    pub p1: Pubkey,
+    pub p2: Pubkey,
+    pub challenge_id: u64,
+    pub p1_fee: u64,
+    pub p2_fee: u64,
+    pub p1_paid: bool,
+    pub p2_paid: bool,
+    pub nft1_mint: Pubkey,
+    pub nft2_mint: Pubkey,
+    pub nft1_status: NFTStatus,
+    pub nft2_status: NFTStatus,
+    pub bump: u8,
+    /// Numbered as `ChallengeStatus` was before `Invited` was added
+    pub status: u8,
+    pub winner: Option<Pubkey>,
+}
+
+impl LegacyChallenge {
+    /// Size of a legacy challenge account, discriminator included
+    pub const SPACE: usize = 8 + 555;
+    /// Legacy `ChallengeStatus::Completed`
+    pub const COMPLETED: u8 = 3;
+    /// Offset of `nft1_status`, `nft2_status` follows it
+    const NFT_STATUS_OFFSET: usize = 8 + 32 * 2 + 8 * 3 + 2 + 32 * 2;
+
+    /// Parse a challenge account's data, failing unless it has the legacy size
+    pub fn try_from_data(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() == Self::SPACE && data.starts_with(Challenge::DISCRIMINATOR),
+            CustomError::InvalidChallengeState
+        );
+        Ok(Self::deserialize(&mut &data[8..])?)
+    }
+
+    /// Store the NFT statuses back into the legacy account data
+    pub fn write_nft_statuses(&self, data: &mut [u8]) {
+        data[Self::NFT_STATUS_OFFSET] = self.nft1_status.clone() as u8;
+        data[Self::NFT_STATUS_OFFSET + 1] = self.nft2_status.clone() as u8;
+    }
+}
+
+/// What a player puts up for a challenge and whether it is in escrow
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum Stake {
+    /// A bundle of NFTs, each in its own escrow token account
+    Nft { nfts: Vec<StakedNft> },
+    /// An amount of an SPL token, held in a token escrow owned by the challenge
+    /// `escrowed` is what reached the escrow, less than `amount` if the mint charges a transfer fee
+    Token { mint: Pubkey, amount: u64, status: NFTStatus, escrowed: u64 },
+    /// Native SOL, held in a `SolEscrow` account
+    Sol { lamports: u64, status: NFTStatus },
+    /// A Bubblegum compressed NFT, escrowed by making the challenge PDA the leaf owner
+    Cnft { merkle_tree: Pubkey, asset_id: Pubkey, status: NFTStatus },
+    /// A Metaplex Core asset, escrowed by making the challenge PDA its owner
+    /// `collection` is read from the asset when it is deposited
+    Core { asset: Pubkey, collection: Option<Pubkey>, status: NFTStatus },
+}
+
+impl Stake {
+    /// A full NFT bundle is the largest variant
+    pub const LEN: usize = 1 + 4 + StakedNft::LEN * Challenge::MAX_BUNDLE_SIZE;
+
+    /// Everything the player staked is in escrow
+    pub fn is_deposited(&self) -> bool {
+        match self {
+            Stake::Nft { nfts } => nfts.iter().all(|nft| nft.status.is_held()),
+            Stake::Token { status, .. }
+            | Stake::Sol { status, .. }
+            | Stake::Cnft { status, .. }
+            | Stake::Core { status, .. } => *status == NFTStatus::Deposited,
+        }
+    }
+
+    /// Some of the stake is still in escrow
+    pub fn holds_deposit(&self) -> bool {
+        match self {
+            Stake::Nft { nfts } => nfts.iter().any(|nft| nft.status.is_held()),
+            Stake::Token { status, .. }
+            | Stake::Sol { status, .. }
+            | Stake::Cnft { status, .. }
+            | Stake::Core { status, .. } => *status == NFTStatus::Deposited,
+        }
+    }
+
+    /// The terms this stake was declared with
+    pub fn terms(&self) -> StakeTerms {
+        match self {
+            Stake::Nft { nfts } => StakeTerms::Nft {
+                mints: nfts.iter().map(|nft| nft.mint).collect(),
+            },
+            Stake::Token { mint, amount, .. } => StakeTerms::Token {
+                mint: *mint,
+                amount: *amount,
+            },
+            Stake::Sol { lamports, .. } => StakeTerms::Sol { lamports: *lamports },
+            Stake::Cnft { merkle_tree, asset_id, .. } => StakeTerms::Cnft {
+                merkle_tree: *merkle_tree,
+                asset_id: *asset_id,
+            },
+            Stake::Core { asset, .. } => StakeTerms::Core { asset: *asset },
+        }
+    }
+
+    /// Replacing this stake with `new` would drop a deposit `restake` can't hand back:
+    /// programmable or locked NFTs, compressed NFTs and Core assets
+    pub fn drops_unreturnable(&self, new: &StakeTerms) -> bool {
+        if self.terms() == *new {
+            return false;
+        }
+        match (self, new) {
+            (Stake::Nft { nfts }, StakeTerms::Nft { mints }) => nfts.iter().any(|nft| {
+                nft.status.is_held() && (nft.programmable || nft.locked) && !mints.contains(&nft.mint)
+            }),
+            (Stake::Nft { nfts }, _) => nfts
+                .iter()
+                .any(|nft| nft.status.is_held() && (nft.programmable || nft.locked)),
+            (Stake::Token { .. } | Stake::Sol { .. }, _) => false,
+            (Stake::Cnft { .. } | Stake::Core { .. }, _) => self.holds_deposit(),
+        }
+    }
+
+    /// Escrow accounts created for this stake, in the order `close_challenge` expects them
+    /// cNFTs and Core assets are owned by the challenge directly and have no escrow account
+    pub fn used_escrows(&self, challenge: &Pubkey, player: &Pubkey) -> Vec<Pubkey> {
+        match self {
+            Stake::Nft { nfts } => nfts
+                .iter()
+                .filter(|nft| nft.status != NFTStatus::NotDeposited && !nft.locked)
+                .map(|nft| nft.escrow(challenge))
+                .collect(),
+            Stake::Token { status, .. } if *status != NFTStatus::NotDeposited => {
+                vec![token_escrow_address(challenge, player)]
+            }
+            Stake::Sol { status, .. } if *status != NFTStatus::NotDeposited => {
+                vec![sol_escrow_address(challenge, player)]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Expiry and single-use nonce carried by every API-signed message
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ApiAuthorization {
+    pub expires_at: i64,
+    pub nonce: u64,
+}
+
+/// Marks an API signer's nonce as used, seeded by the signer and nonce
+/// Closable with `close_used_nonce` once `expires_at` has passed, as the authorization can't be replayed then
+#[account]
+pub struct UsedNonce {
+    pub expires_at: i64,
+    /// Paid the account's rent and receives it back when it is closed
+    pub rent_payer: Pubkey,
+}
+
+impl UsedNonce {
+    pub const LEN: usize = 8 + 32;
+}
+
+/// Who may take the p2 slot of an open challenge
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct JoinConstraints {
+    /// Every NFT the joiner stakes must be in this verified collection
+    pub required_collection: Option<Pubkey>,
+    /// Highest service fee the API may charge the joiner
+    pub max_fee: Option<u64>,
+    /// Players allowed to join, anyone if empty
+    pub allowlist: Vec<Pubkey>,
+}
+
+impl JoinConstraints {
+    pub const MAX_ALLOWLIST: usize = 10;
+    pub const LEN: usize = 33 + 9 + 4 + 32 * Self::MAX_ALLOWLIST;
+}
+
+/// Stakes and fees a player proposes to replace a challenge's current terms with
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct StakeChange {
+    pub p1_fee: u64,
+    pub p2_fee: u64,
+    pub p1_stake: StakeTerms,
+    pub p2_stake: StakeTerms,
+}
+
+impl StakeChange {
+    pub const LEN: usize = 8 + 8 + 2 * StakeTerms::LEN;
+}
+
+/// A stake change waiting for the player who didn't propose it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct ProposedStakeChange {
+    pub proposer: Pubkey,
+    pub change: StakeChange,
+}
+
+impl ProposedStakeChange {
+    pub const LEN: usize = 32 + StakeChange::LEN;
+}
+
+/// A player proposed new terms for a challenge
+#[event]
+pub struct StakeChangeProposed {
+    pub challenge: Pubkey,
+    pub proposer: Pubkey,
+    pub change: StakeChange,
+}
+
+/// The other player accepted a proposal, `previous` are the terms it replaced
+#[event]
+pub struct StakeChangeAccepted {
+    pub challenge: Pubkey,
+    pub accepter: Pubkey,
+    pub previous: StakeChange,
+    pub change: StakeChange,
+}
+
+/// Everything that defines a new challenge, as signed by the API for `initialize_attested_challenge`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ChallengeParams {
+    pub challenge_id: u64,
+    pub p1_fee: u64,
+    pub p2_fee: u64,
+    pub p1_stake: StakeTerms,
+    pub p2_stake: StakeTerms,
+    pub deadlines: ChallengeDeadlines,
+    pub match_rule: MatchRule,
+}
+
+/// How closely the two stakes must match, chosen when the challenge is created
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum MatchRule {
+    Any,
+    /// Every NFT or Core asset on both sides is in the same verified collection
+    /// Only NFT and Core stakes qualify
+    SameCollection,
+    /// Both stakes are in the same value tier, as attested by an API signer
+    /// The attested values are kept for audit
+    ValueTier { p1_value: u64, p2_value: u64, p1_tier: u8, p2_tier: u8 },
+}
+
+impl MatchRule {
+    pub const LEN: usize = 1 + 8 + 8 + 1 + 1;
+}
+
+/// A player's stake as declared when the challenge is created
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum StakeTerms {
+    Nft { mints: Vec<Pubkey> },
+    Token { mint: Pubkey, amount: u64 },
+    Sol { lamports: u64 },
+    Cnft { merkle_tree: Pubkey, asset_id: Pubkey },
+    Core { asset: Pubkey },
+}
+
+impl StakeTerms {
+    /// Sized for a full NFT bundle
+    pub const LEN: usize = 1 + 4 + 32 * Challenge::MAX_BUNDLE_SIZE;
+
+    /// Every stake is well formed and no NFT is staked twice
+    pub fn validate_stakes(stakes: &[&StakeTerms]) -> Result<()> {
+        for terms in stakes {
+            match terms {
+                StakeTerms::Nft { mints } => require!(
+                    !mints.is_empty() && mints.len() <= Challenge::MAX_BUNDLE_SIZE,
+                    CustomError::InvalidBundle
+                ),
+                StakeTerms::Token { amount, .. } => {
+                    require!(*amount > 0, CustomError::InvalidStakeAmount)
+                }
+                StakeTerms::Sol { lamports } => {
+                    require!(*lamports > 0, CustomError::InvalidStakeAmount)
+                }
+                StakeTerms::Cnft { merkle_tree, asset_id } => require!(
+                    *merkle_tree != Pubkey::default() && *asset_id != Pubkey::default(),
+                    CustomError::InvalidCnftAsset
+                ),
+                StakeTerms::Core { asset } => {
+                    require!(*asset != Pubkey::default(), CustomError::InvalidCoreAsset)
+                }
+            }
+        }
+
+        let all_assets = stakes
+            .iter()
+            .flat_map(|terms| match terms {
+                StakeTerms::Nft { mints } => mints.clone(),
+                StakeTerms::Cnft { asset_id, .. } => vec![*asset_id],
+                StakeTerms::Core { asset } => vec![*asset],
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+        let distinct = all_assets
+            .iter()
+            .enumerate()
+            .all(|(i, asset)| !all_assets[..i].contains(asset));
+        require!(distinct, CustomError::InvalidBundle);
+
+        Ok(())
+    }
+
+    /// Whether the stake's collection is read on-chain, as `MatchRule::SameCollection` needs
+    pub fn has_collection(&self) -> bool {
+        matches!(self, StakeTerms::Nft { .. } | StakeTerms::Core { .. })
+    }
+}
+
+impl From<StakeTerms> for Stake {
+    fn from(terms: StakeTerms) -> Self {
+        match terms {
+            StakeTerms::Nft { mints } => Stake::Nft {
+                nfts: mints.into_iter().map(StakedNft::new).collect(),
+            },
+            StakeTerms::Token { mint, amount } => Stake::Token {
+                mint,
+                amount,
+                status: NFTStatus::NotDeposited,
+                escrowed: 0,
+            },
+            StakeTerms::Sol { lamports } => Stake::Sol {
+                lamports,
+                status: NFTStatus::NotDeposited,
+            },
+            StakeTerms::Cnft { merkle_tree, asset_id } => Stake::Cnft {
+                merkle_tree,
+                asset_id,
+                status: NFTStatus::NotDeposited,
+            },
+            StakeTerms::Core { asset } => Stake::Core {
+                asset,
+                collection: None,
+                status: NFTStatus::NotDeposited,
+            },
+        }
+    }
+}
+
+/// One NFT of a player's bundle and where it currently is
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct StakedNft {
+    pub mint: Pubkey,
+    pub status: NFTStatus,
+    /// Deposited through Token Metadata into the challenge's associated token account
+    pub programmable: bool,
+    /// Frozen in the player's own token account by `lock_nft` rather than escrowed
+    pub locked: bool,
+}
+
+impl StakedNft {
+    pub const LEN: usize = 32 + 1 + 1 + 1;
+
+    pub fn new(mint: Pubkey) -> Self {
+        Self {
+            mint,
+            status: NFTStatus::NotDeposited,
+            programmable: false,
+            locked: false,
+        }
+    }
+
+    /// Where this NFT is escrowed for `challenge`
+    pub fn escrow(&self, challenge: &Pubkey) -> Pubkey {
+        if self.programmable {
+            get_associated_token_address(challenge, &self.mint)
+        } else {
+            escrow_address(challenge, &self.mint).0
+        }
+    }
+}
+
+/// The current state of a cNFT leaf, as read from an indexer for Bubblegum `transfer`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct CnftLeaf {
+    pub root: [u8; 32],
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+    pub nonce: u64,
+    pub index: u32,
+}
+
+/// Where a staked NFT, or a token or SOL stake, currently is
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+#[repr(u8)]
 pub enum NFTStatus {
     NotDeposited,
     Deposited,
     Claimed,
-    Refunded
+    Refunded,
+    /// Frozen in the owner's wallet with the challenge PDA as delegate
+    Locked,
+}
+
+impl NFTStatus {
+    /// Committed to the challenge, either escrowed or locked
+    pub fn is_held(&self) -> bool {
+        matches!(self, NFTStatus::Deposited | NFTStatus::Locked)
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::MAX_SIZE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// CHECK: May still have an older layout, `migrate_config` resizes it before deserializing
+    #[account(mut, seeds = [b"config"], bump, owner = crate::ID)]
+    pub config: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct InitConfigParams {
+    pub chain_id: [u8; 16],
+    pub treasury: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(collection: Pubkey)]
+pub struct AddAllowedCollection<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + AllowedCollection::LEN,
+        seeds = [b"allowed_collection", collection.as_ref()],
+        bump
+    )]
+    pub allowed_collection: Account<'info, AllowedCollection>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAllowedCollection<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"allowed_collection", allowed_collection.collection.as_ref()],
+        bump = allowed_collection.bump
+    )]
+    pub allowed_collection: Account<'info, AllowedCollection>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveAllowedCollection<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"allowed_collection", allowed_collection.collection.as_ref()],
+        bump = allowed_collection.bump
+    )]
+    pub allowed_collection: Account<'info, AllowedCollection>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub new_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(challenge_id: u64)]
+pub struct InitializeChallenge<'info> {
+    #[account(mut)]
+    pub p1: Signer<'info>,
+    /// CHECK: Only stored, p2 must sign `accept_challenge` before the challenge proceeds
+    pub p2: UncheckedAccount<'info>,
+    /// CHECK: Must be a signer and in the list of trusted API wallets
+    #[account()]
+    pub api_signer: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = p1,
+        space = 8 + Challenge::LEN,
+        seeds = [b"challenge", p1.key().as_ref(), &challenge_id.to_le_bytes()],
+        bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    /// CHECK: Instructions sysvar, read for API price attestations
+    #[account(address = instructions_sysvar::ID)]
+    pub sysvar_instructions: UncheckedAccount<'info>,
+    /// CHECK: The price attestation's nonce PDA, created by `consume_api_nonce`
+    #[account(mut)]
+    pub api_nonce: Option<UncheckedAccount<'info>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(params: ChallengeParams)]
+pub struct InitializeAttestedChallenge<'info> {
+    #[account(mut)]
+    pub p1: Signer<'info>,
+    /// CHECK: Only stored, p2 must sign `accept_challenge` before the challenge proceeds
+    pub p2: UncheckedAccount<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = p1,
+        space = 8 + Challenge::LEN,
+        seeds = [b"challenge", p1.key().as_ref(), &params.challenge_id.to_le_bytes()],
+        bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    /// CHECK: Instructions sysvar, read for the API's challenge attestation
+    #[account(address = instructions_sysvar::ID)]
+    pub sysvar_instructions: UncheckedAccount<'info>,
+    /// CHECK: The attestation's nonce PDA, created by `consume_api_nonce`
+    #[account(mut)]
+    pub api_nonce: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUsedNonce<'info> {
+    #[account(mut, close = rent_payer, has_one = rent_payer)]
+    pub api_nonce: Account<'info, UsedNonce>,
+    /// CHECK: Receives the nonce rent, must match api_nonce.rent_payer
+    #[account(mut)]
+    pub rent_payer: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(challenge_id: u64)]
+pub struct InitializeOpenChallenge<'info> {
+    #[account(mut)]
+    pub p1: Signer<'info>,
+    /// CHECK: Must be a signer and in the list of trusted API wallets
+    pub api_signer: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = p1,
+        space = 8 + Challenge::LEN,
+        seeds = [b"challenge", p1.key().as_ref(), &challenge_id.to_le_bytes()],
+        bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinChallenge<'info> {
+    pub p2: Signer<'info>,
+    /// CHECK: Must be a signer and in the list of trusted API wallets
+    pub api_signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOpenChallenge<'info> {
+    #[account(mut)]
+    pub p1: Signer<'info>,
+
+    #[account(
+        mut,
+        close = p1,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        has_one = p1 @ CustomError::Unauthorized
+    )]
+    pub challenge: Account<'info, Challenge>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptChallenge<'info> {
+    #[account(mut)]
+    pub p2: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        has_one = p2 @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeclineChallenge<'info> {
+    pub p2: Signer<'info>,
+
+    /// CHECK: Receives the challenge rent, must match challenge.p1
+    #[account(mut, address = challenge.p1)]
+    pub p1: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        close = p1,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        has_one = p2 @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeStakeChange<'info> {
+    pub proposer: Signer<'info>,
+    /// CHECK: Must be a signer and in the list of trusted API wallets
+    pub api_signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptStakeChange<'info> {
+    pub accepter: Signer<'info>,
+
+    /// CHECK: Receives p1's returned escrow rent and fee, must match challenge.p1
+    #[account(mut, address = challenge.p1)]
+    pub p1: UncheckedAccount<'info>,
+
+    /// CHECK: Receives p2's returned escrow rent and fee, must match challenge.p2
+    #[account(mut, address = challenge.p2)]
+    pub p2: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawDeposit<'info> {
+    #[account(mut)]
+    pub player: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct MutualCancel<'info> {
+    #[account(mut)]
+    pub p1: Signer<'info>,
+    #[account(mut)]
+    pub p2: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        has_one = p1 @ CustomError::InvalidPayer,
+        has_one = p2 @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct PayServiceFee<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositNft<'info> {
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        constraint = challenge.is_player(&depositor.key()) @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// One of the mints in the depositor's bundle, checked by `escrow_nft`
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
+    /// User's token account that holds the NFT to be transferred, checked by `escrow_nft`
+    #[account(mut)]
+    pub from_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Escrow token account that will hold the NFT
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        seeds = [b"escrow", challenge.key().as_ref(), nft_mint.key().as_ref()],
+        bump,
+        token::mint = nft_mint,
+        token::authority = challenge,
+        token::token_program = token_program
+    )]
+    pub escrow_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: `nft_mint`'s Metaplex metadata, only needed when the collection allowlist is enforced
+    pub nft_metadata: Option<UncheckedAccount<'info>>,
+
+    /// Allowlist entry for the NFT's collection, only needed when the allowlist is enforced
+    pub allowed_collection: Option<Account<'info, AllowedCollection>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveChallenge<'info> {
+    #[account(mut)]
+    pub moderator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireChallenge<'info> {
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Treasury account passed in must match config.treasury
+    #[account(
+        mut,
+        address = config.treasury
+    )]
+    pub treasury: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFeeRefund<'info> {
+    #[account(mut)]
+    pub claimer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimWinnings<'info> {
+    #[account(mut)]
+    pub claimer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRefundNft<'info> {
+    #[account(mut)]
+    pub claimer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// One of the mints in the claimer's bundle
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
+    /// Escrow token account holding the claimer's own NFT
+    #[account(
+        mut,
+        seeds = [b"escrow", challenge.key().as_ref(), nft_mint.key().as_ref()],
+        bump,
+        token::mint = nft_mint,
+        token::authority = challenge
+    )]
+    pub escrow_nft_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Token account to return the NFT to (must be owned by `claimer`)
+    #[account(mut, token::mint = nft_mint)]
+    pub destination_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+/// Shared by `deposit_pnft`, `claim_winner_pnft` and `claim_refund_pnft`
+#[derive(Accounts)]
+pub struct PnftEscrow<'info> {
+    /// The depositor, winner or refunded player
+    #[account(mut)]
+    pub player: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        constraint = challenge.is_player(&player.key()) @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: The player's associated token account, created by Token Metadata if needed
+    #[account(mut, address = get_associated_token_address(&player.key(), &nft_mint.key()))]
+    pub player_token_account: UncheckedAccount<'info>,
+
+    /// CHECK: The challenge's associated token account, created by Token Metadata if needed
+    #[account(mut, address = get_associated_token_address(&challenge.key(), &nft_mint.key()))]
+    pub escrow_token_account: UncheckedAccount<'info>,
+
+    /// CHECK: Checked by Token Metadata
+    #[account(mut)]
+    pub metadata: UncheckedAccount<'info>,
+
+    /// CHECK: Master edition, checked by Token Metadata
+    pub edition: UncheckedAccount<'info>,
+
+    /// CHECK: Token record of `player_token_account`, checked by Token Metadata
+    #[account(mut)]
+    pub player_token_record: UncheckedAccount<'info>,
+
+    /// CHECK: Token record of `escrow_token_account`, checked by Token Metadata
+    #[account(mut)]
+    pub escrow_token_record: UncheckedAccount<'info>,
+
+    /// CHECK: Only needed if the pNFT has a rule set, checked by Token Metadata
+    pub authorization_rules_program: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Only needed if the pNFT has a rule set, checked by Token Metadata
+    pub authorization_rules: Option<UncheckedAccount<'info>>,
+
+    /// Allowlist entry for the pNFT's collection, only needed by `deposit_pnft` when the allowlist is enforced
+    pub allowed_collection: Option<Account<'info, AllowedCollection>>,
+
+    /// CHECK: Instructions sysvar
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub sysvar_instructions: UncheckedAccount<'info>,
+
+    pub token_metadata_program: Program<'info, TokenMetadata>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Shared by `lock_nft` and `unlock_nft`
+#[derive(Accounts)]
+pub struct LockNft<'info> {
+    /// The NFT's owner
+    #[account(mut)]
+    pub player: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        constraint = challenge.is_player(&player.key()) @ CustomError::InvalidPayer
+    )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mint::token_program = token_program)]
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
+    /// The token account the NFT is locked in
+    #[account(
+        mut,
+        token::mint = nft_mint,
+        token::authority = player,
+        token::token_program = token_program
+    )]
+    pub player_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Master edition, the mint's freeze authority, checked by Token Metadata
+    pub edition: UncheckedAccount<'info>,
+
+    /// CHECK: `nft_mint`'s Metaplex metadata, only needed when the collection allowlist is enforced
+    pub nft_metadata: Option<UncheckedAccount<'info>>,
+
+    /// Allowlist entry for the NFT's collection, only needed when the allowlist is enforced
+    pub allowed_collection: Option<Account<'info, AllowedCollection>>,
+
+    pub token_metadata_program: Program<'info, TokenMetadata>,
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct InitializeConfig<'info> {
+pub struct ClaimLockedNft<'info> {
     #[account(mut)]
-    pub admin: Signer<'info>,
+    pub winner: Signer<'info>,
+
     #[account(
-        init,
-        payer = admin,
-        space = 8 + Config::MAX_SIZE,
-        seeds = [b"config"],
-        bump
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
     )]
+    pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
     pub config: Account<'info, Config>,
-    pub system_program: Program<'info, System>,
-}
 
-#[derive(AnchorSerialize, AnchorDeserialize)]
-pub struct InitConfigParams {
-    pub chain_id: [u8; 16],
-    pub treasury: Pubkey,
-}
+    #[account(mint::token_program = token_program)]
+    pub nft_mint: InterfaceAccount<'info, Mint>,
 
-#[derive(Accounts)]
-pub struct UpdateConfig<'info> {
-    #[account(mut, seeds = [b"config"], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    pub admin: Signer<'info>,
+    /// The loser's token account the NFT is locked in
+    #[account(
+        mut,
+        token::mint = nft_mint,
+        token::token_program = token_program
+    )]
+    pub locked_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = winner,
+        associated_token::mint = nft_mint,
+        associated_token::authority = winner,
+        associated_token::token_program = token_program
+    )]
+    pub destination_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Master edition, the mint's freeze authority, checked by Token Metadata
+    pub edition: UncheckedAccount<'info>,
+
+    pub token_metadata_program: Program<'info, TokenMetadata>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
 }
 
+/// Shared by `deposit_cnft`, `claim_winner_cnft` and `claim_refund_cnft`
 #[derive(Accounts)]
-#[instruction(challenge_id: u64)]
-pub struct InitializeChallenge<'info> {
+pub struct CnftEscrow<'info> {
+    /// The depositor, winner or refunded player
     #[account(mut)]
-    pub p1: Signer<'info>,
-    /// CHECK: Only stored, not used
-    pub p2: UncheckedAccount<'info>,
-    /// CHECK: Must be a signer and in the list of trusted API wallets
-    #[account()]
-    pub api_signer: Signer<'info>,
-    #[account(seeds = [b"config"], bump = config.bump)]
-    pub config: Account<'info, Config>,
+    pub player: Signer<'info>,
+
     #[account(
-        init,
-        payer = p1,
-        space = 8 + Challenge::LEN,
-        seeds = [b"challenge", p1.key().as_ref(), &challenge_id.to_le_bytes()],
-        bump
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump,
+        constraint = challenge.is_player(&player.key()) @ CustomError::InvalidPayer
     )]
     pub challenge: Account<'info, Challenge>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Tree authority PDA, checked by Bubblegum
+    pub tree_config: UncheckedAccount<'info>,
+
+    /// CHECK: Matched against the staked cNFT, proof checked by Account Compression
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    /// CHECK: SPL Noop program
+    #[account(address = SPL_NOOP_ID)]
+    pub log_wrapper: UncheckedAccount<'info>,
+
+    /// CHECK: SPL Account Compression program
+    #[account(address = SPL_ACCOUNT_COMPRESSION_ID)]
+    pub compression_program: UncheckedAccount<'info>,
+
+    /// CHECK: Bubblegum program
+    #[account(address = mpl_bubblegum::ID)]
+    pub bubblegum_program: UncheckedAccount<'info>,
+
     pub system_program: Program<'info, System>,
 }
 
+/// Shared by `deposit_core`, `claim_winner_core` and `claim_refund_core`
 #[derive(Accounts)]
-pub struct PayServiceFee<'info> {
+pub struct CoreEscrow<'info> {
+    /// The depositor, winner or refunded player
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub player: Signer<'info>,
 
     #[account(
         mut,
         seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
-        bump = challenge.bump
+        bump = challenge.bump,
+        constraint = challenge.is_player(&player.key()) @ CustomError::InvalidPayer
     )]
     pub challenge: Account<'info, Challenge>,
 
-    #[account(
-        seeds = [b"config"],
-        bump = config.bump
-    )]
+    #[account(seeds = [b"config"], bump = config.bump)]
     pub config: Account<'info, Config>,
 
-    /// CHECK: Treasury account passed in must match config.treasury
-    #[account(
-        mut,
-        address = config.treasury
-    )]
-    pub treasury: UncheckedAccount<'info>,
+    /// CHECK: Matched against the staked asset, checked by mpl-core
+    #[account(mut, owner = mpl_core::ID)]
+    pub asset: UncheckedAccount<'info>,
+
+    /// CHECK: The asset's collection if it has one, checked by mpl-core
+    #[account(mut)]
+    pub collection: Option<UncheckedAccount<'info>>,
+
+    /// Allowlist entry for the asset's collection, only needed by `deposit_core` when the allowlist is enforced
+    pub allowed_collection: Option<Account<'info, AllowedCollection>>,
+
+    /// CHECK: mpl-core program
+    #[account(address = mpl_core::ID)]
+    pub core_program: UncheckedAccount<'info>,
 
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct DepositNft<'info> {
+pub struct DepositTokens<'info> {
     #[account(mut)]
     pub depositor: Signer<'info>,
 
     #[account(
         mut,
         seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
-        bump = challenge.bump
+        bump = challenge.bump,
+        constraint = challenge.is_player(&depositor.key()) @ CustomError::InvalidPayer
     )]
     pub challenge: Account<'info, Challenge>,
 
-    /// User's token account that holds the NFT to be transferred
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// The token mint declared on the depositor's stake
+    /// Writable so transfer fees withheld in the escrow can be harvested to it
     #[account(mut)]
-    pub from_token_account: Account<'info, TokenAccount>,
+    pub mint: InterfaceAccount<'info, Mint>,
 
-    /// Escrow token account that will hold the NFT
+    /// User's token account holding the stake
+    #[account(
+        mut,
+        token::mint = mint,
+        constraint = from_token_account.owner == depositor.key() @ CustomError::SourceOwnerMismatch
+    )]
+    pub from_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Escrow token account that will hold the depositor's stake
     #[account(
         init_if_needed,
         payer = depositor,
-        seeds = [b"escrow", challenge.key().as_ref(), nft_mint.key().as_ref()],
+        seeds = [b"token_escrow", challenge.key().as_ref(), depositor.key().as_ref()],
         bump,
-        token::mint = nft_mint,
-        token::authority = challenge
+        token::mint = mint,
+        token::authority = challenge,
+        token::token_program = token_program
     )]
-    pub escrow_token_account: Account<'info, TokenAccount>,
+    pub escrow_token_account: InterfaceAccount<'info, TokenAccount>,
 
-    pub nft_mint: Account<'info, Mint>,
-
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct ResolveChallenge<'info> {
+pub struct DepositSol<'info> {
     #[account(mut)]
-    pub moderator: Signer<'info>,
+    pub depositor: Signer<'info>,
+
     #[account(
         mut,
         seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
-        bump = challenge.bump
+        bump = challenge.bump,
+        constraint = challenge.is_player(&depositor.key()) @ CustomError::InvalidPayer
     )]
     pub challenge: Account<'info, Challenge>,
+
     #[account(seeds = [b"config"], bump = config.bump)]
     pub config: Account<'info, Config>,
+
+    /// Escrow that will hold the depositor's SOL stake
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        space = 8 + SolEscrow::LEN,
+        seeds = [b"sol_escrow", challenge.key().as_ref(), depositor.key().as_ref()],
+        bump
+    )]
+    pub sol_escrow: Account<'info, SolEscrow>,
+
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimWinnerNfts<'info> {
+pub struct ClaimRefundTokens<'info> {
     #[account(mut)]
     pub claimer: Signer<'info>,
 
@@ -617,67 +4906,442 @@ pub struct ClaimWinnerNfts<'info> {
     )]
     pub challenge: Account<'info, Challenge>,
 
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    /// Escrow token account holding the claimer's own stake
     #[account(
         mut,
-        seeds = [b"escrow", challenge.key().as_ref(), nft1_mint.key().as_ref()],
+        seeds = [b"token_escrow", challenge.key().as_ref(), claimer.key().as_ref()],
         bump,
-        token::mint = nft1_mint,
+        token::mint = mint,
         token::authority = challenge
     )]
-    pub escrow_nft1_account: Account<'info, TokenAccount>,
+    pub escrow_token_account: InterfaceAccount<'info, TokenAccount>,
 
+    /// Token account to return the stake to (must be owned by `claimer`)
     #[account(
         mut,
-        seeds = [b"escrow", challenge.key().as_ref(), nft2_mint.key().as_ref()],
-        bump,
-        token::mint = nft2_mint,
-        token::authority = challenge
+        token::mint = mint,
+        constraint = destination_token_account.owner == claimer.key() @ CustomError::InvalidPayer
     )]
-    pub escrow_nft2_account: Account<'info, TokenAccount>,
+    pub destination_token_account: InterfaceAccount<'info, TokenAccount>,
 
-    #[account(
-        init_if_needed,
-        payer = claimer,
-        associated_token::mint = nft1_mint,
-        associated_token::authority = claimer
-    )]
-    pub winner_nft1_account: Account<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRefundSol<'info> {
+    #[account(mut)]
+    pub claimer: Signer<'info>,
 
     #[account(
-        init_if_needed,
-        payer = claimer,
-        associated_token::mint = nft2_mint,
-        associated_token::authority = claimer
+        mut,
+        seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
+        bump = challenge.bump
     )]
-    pub winner_nft2_account: Account<'info, TokenAccount>,
+    pub challenge: Account<'info, Challenge>,
 
-    pub nft1_mint: Account<'info, Mint>,
-    pub nft2_mint: Account<'info, Mint>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+    /// Escrow holding the claimer's own SOL stake
+    #[account(
+        mut,
+        seeds = [b"sol_escrow", challenge.key().as_ref(), claimer.key().as_ref()],
+        bump = sol_escrow.bump
+    )]
+    pub sol_escrow: Account<'info, SolEscrow>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimRefundNft<'info> {
-    #[account(mut)]
-    pub claimer: Signer<'info>,
-
+pub struct CloseChallenge<'info> {
     #[account(
         mut,
+        close = rent_payer,
         seeds = [b"challenge", challenge.p1.as_ref(), &challenge.challenge_id.to_le_bytes()],
-        bump = challenge.bump
+        bump = challenge.bump,
+        has_one = rent_payer
     )]
     pub challenge: Account<'info, Challenge>,
 
+    /// CHECK: Receives the challenge rent, must match challenge.rent_payer
     #[account(mut)]
-    pub escrow_nft_account: Account<'info, TokenAccount>,
+    pub rent_payer: UncheckedAccount<'info>,
 
-    /// Token account to return the NFT to (must be owned by `claimer`)
+    /// CHECK: Receives the rent of p1's escrows, must match challenge.p1
+    #[account(mut, address = challenge.p1)]
+    pub p1: UncheckedAccount<'info>,
+
+    /// CHECK: Receives the rent of p2's escrows, must match challenge.p2
+    #[account(mut, address = challenge.p2)]
+    pub p2: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub token_2022_program: Program<'info, Token2022>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimLegacyNft<'info> {
+    /// The winner of a completed legacy challenge, otherwise the NFT's depositor
+    pub claimer: Signer<'info>,
+
+    /// CHECK: A challenge in the legacy layout, parsed and checked by `LegacyChallenge`
+    #[account(mut, owner = crate::ID)]
+    pub challenge: UncheckedAccount<'info>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Receives the challenge rent once it is emptied, must match the legacy p1
+    #[account(mut)]
+    pub p1: UncheckedAccount<'info>,
+
+    /// CHECK: Receives the escrow rent, must be the player who deposited `nft_mint`
     #[account(mut)]
-    pub destination_token_account: Account<'info, TokenAccount>,
+    pub depositor: UncheckedAccount<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub nft_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"escrow", challenge.key().as_ref(), nft_mint.key().as_ref()],
+        bump,
+        token::mint = nft_mint,
+        token::authority = challenge,
+        token::token_program = token_program
+    )]
+    pub escrow_nft_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = nft_mint,
+        token::authority = claimer,
+        token::token_program = token_program
+    )]
+    pub destination_token_account: InterfaceAccount<'info, TokenAccount>,
 
     pub token_program: Program<'info, Token>,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A challenge in `status` where each player staked a one NFT bundle
+    fn challenge(
+        status: ChallengeStatus,
+        (p1, p1_nft): (Pubkey, StakedNft),
+        (p2, p2_nft): (Pubkey, StakedNft),
+    ) -> Challenge {
+        Challenge {
+            p1,
+            p2,
+            challenge_id: 0,
+            p1_fee: 0,
+            p2_fee: 0,
+            p1_paid: true,
+            p2_paid: true,
+            p1_stake: Stake::Nft { nfts: vec![p1_nft] },
+            p2_stake: Stake::Nft { nfts: vec![p2_nft] },
+            bump: 255,
+            cancelled: status == ChallengeStatus::Cancelled,
+            drawn: status == ChallengeStatus::Drawn,
+            status,
+            winner: None,
+            moderator_votes: vec![],
+            votes_for_p1: 0,
+            votes_for_p2: 0,
+            votes_to_cancel: 0,
+            resolution_threshold: 3,
+            deadlines: ChallengeDeadlines { fee: 0, escrow: 0, resolution: 0 },
+            fees_collected: false,
+            p1_fee_refunded: false,
+            p2_fee_refunded: false,
+            rent_payer: p1,
+            votes_for_draw: 0,
+            refund_fees_on_draw: false,
+            match_rule: MatchRule::Any,
+            join_constraints: None,
+            proposed_change: None,
+        }
+    }
+
+    fn deposited(mint: Pubkey) -> StakedNft {
+        StakedNft {
+            status: NFTStatus::Deposited,
+            ..StakedNft::new(mint)
+        }
+    }
+
+    /// A challenge in `status` where both players deposited a one NFT bundle
+    fn deposited_challenge(
+        status: ChallengeStatus,
+        p1: Pubkey,
+        p2: Pubkey,
+        p1_mint: Pubkey,
+        p2_mint: Pubkey,
+    ) -> Challenge {
+        challenge(status, (p1, deposited(p1_mint)), (p2, deposited(p2_mint)))
+    }
+
+    fn nft_status(stake: &Stake) -> NFTStatus {
+        let Stake::Nft { nfts } = stake else {
+            panic!("expected an NFT stake");
+        };
+        nfts[0].status.clone()
+    }
+
+    #[test]
+    fn refunds_both_players_after_cancellation() {
+        let (p1, p2, p1_mint, p2_mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut challenge =
+            deposited_challenge(ChallengeStatus::Cancelled, p1, p2, p1_mint, p2_mint);
+
+        challenge.refund_nft(&p1, &p1_mint).unwrap();
+        assert!(nft_status(&challenge.p1_stake) == NFTStatus::Refunded);
+        assert!(nft_status(&challenge.p2_stake) == NFTStatus::Deposited);
+
+        challenge.refund_nft(&p2, &p2_mint).unwrap();
+        assert!(nft_status(&challenge.p2_stake) == NFTStatus::Refunded);
+        assert!(!challenge.p1_stake.holds_deposit() && !challenge.p2_stake.holds_deposit());
+    }
+
+    #[test]
+    fn refunds_only_the_claimers_own_nft() {
+        let (p1, p2, p1_mint, p2_mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut challenge =
+            deposited_challenge(ChallengeStatus::Cancelled, p1, p2, p1_mint, p2_mint);
+
+        assert_eq!(
+            challenge.refund_nft(&p1, &p2_mint).unwrap_err(),
+            CustomError::InvalidNftMint.into()
+        );
+        assert_eq!(
+            challenge.refund_nft(&Pubkey::new_unique(), &p1_mint).unwrap_err(),
+            CustomError::InvalidPayer.into()
+        );
+        challenge.refund_nft(&p1, &p1_mint).unwrap();
+        assert_eq!(
+            challenge.refund_nft(&p1, &p1_mint).unwrap_err(),
+            CustomError::InvalidChallengeState.into()
+        );
+    }
+
+    #[test]
+    fn refunds_need_a_cancelled_or_drawn_challenge() {
+        let (p1, p2, p1_mint, p2_mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut ready = deposited_challenge(ChallengeStatus::Ready, p1, p2, p1_mint, p2_mint);
+        assert_eq!(
+            ready.refund_nft(&p1, &p1_mint).unwrap_err(),
+            CustomError::InvalidChallengeState.into()
+        );
+
+        let mut drawn = deposited_challenge(ChallengeStatus::Drawn, p1, p2, p1_mint, p2_mint);
+        drawn.refund_nft(&p2, &p2_mint).unwrap();
+    }
+
+    #[test]
+    fn refunds_are_signed_for_the_challenge_pda() {
+        let (p1, p2) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut challenge = deposited_challenge(
+            ChallengeStatus::Cancelled,
+            p1,
+            p2,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        challenge.challenge_id = 42;
+        let (address, bump) = Pubkey::find_program_address(
+            &[b"challenge", p1.as_ref(), &42u64.to_le_bytes()],
+            &crate::ID,
+        );
+        challenge.bump = bump;
+
+        // The escrow's authority, so the token program accepts the challenge's signature
+        let signer_seeds = challenge_signer_seeds(&challenge);
+        assert_eq!(
+            Pubkey::create_program_address(&signer_seeds.as_slices(), &crate::ID).unwrap(),
+            address
+        );
+
+        // Another challenge's seeds can't sign for it
+        challenge.challenge_id = 43;
+        let other_seeds = challenge_signer_seeds(&challenge);
+        assert_ne!(
+            Pubkey::create_program_address(&other_seeds.as_slices(), &crate::ID).ok(),
+            Some(address)
+        );
+    }
+
+    #[test]
+    fn legacy_challenges_keep_their_layout() {
+        let (p1, p2, nft1, nft2, winner) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        // A completed challenge as the program wrote it before bundle stakes
+        let mut data = Challenge::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(p1.as_ref());
+        data.extend_from_slice(p2.as_ref());
+        data.extend_from_slice(&7u64.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(nft1.as_ref());
+        data.extend_from_slice(nft2.as_ref());
+        data.extend_from_slice(&[1, 1, 254, 3, 1]);
+        data.extend_from_slice(winner.as_ref());
+        data.resize(LegacyChallenge::SPACE, 0);
+
+        let mut legacy = LegacyChallenge::try_from_data(&data).unwrap();
+        assert_eq!((legacy.p1, legacy.p2, legacy.challenge_id), (p1, p2, 7));
+        assert_eq!((legacy.nft1_mint, legacy.nft2_mint), (nft1, nft2));
+        assert_eq!((legacy.bump, legacy.status), (254, LegacyChallenge::COMPLETED));
+        assert_eq!(legacy.winner, Some(winner));
+
+        legacy.nft2_status = NFTStatus::Claimed;
+        legacy.write_nft_statuses(&mut data);
+        let legacy = LegacyChallenge::try_from_data(&data).unwrap();
+        assert!(legacy.nft1_status == NFTStatus::Deposited);
+        assert!(legacy.nft2_status == NFTStatus::Claimed);
+        assert_eq!(legacy.winner, Some(winner));
+
+        // Current challenges are never read as legacy ones
+        data.resize(8 + Challenge::LEN, 0);
+        assert_eq!(
+            LegacyChallenge::try_from_data(&data).err().unwrap(),
+            CustomError::InvalidChallengeState.into()
+        );
+    }
+
+    #[test]
+    fn allowlist_rejects_cnft_stakes() {
+        let cnft = StakeTerms::Cnft {
+            merkle_tree: Pubkey::new_unique(),
+            asset_id: Pubkey::new_unique(),
+        };
+        assert_eq!(
+            bundle_collections(&[&cnft], &[], true).unwrap_err(),
+            CustomError::CnftNotAllowlistable.into()
+        );
+        assert!(bundle_collections(&[&cnft], &[], false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn allowlist_needs_core_asset_accounts() {
+        let core = StakeTerms::Core { asset: Pubkey::new_unique() };
+        assert_eq!(
+            bundle_collections(&[&core], &[], true).unwrap_err(),
+            CustomError::InvalidRemainingAccounts.into()
+        );
+        assert_eq!(
+            bundle_collections(&[&core], &[], false).unwrap_err(),
+            CustomError::InvalidRemainingAccounts.into()
+        );
+    }
+
+    #[test]
+    fn core_assets_share_their_collection() {
+        let (asset, owner, collection) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        // BaseAssetV1: key, owner, update authority, empty name and uri, no seq
+        let mut data = vec![1];
+        data.extend_from_slice(owner.as_ref());
+        data.push(2);
+        data.extend_from_slice(collection.as_ref());
+        data.extend_from_slice(&[0; 9]);
+        let mut lamports = 0;
+        let core_program = mpl_core::ID;
+        let accounts = [AccountInfo::new(
+            &asset, false, false, &mut lamports, &mut data, &core_program, false, 0,
+        )];
+
+        let core = StakeTerms::Core { asset };
+        let nft = StakeTerms::Nft { mints: vec![Pubkey::new_unique()] };
+        assert!(core.has_collection() && nft.has_collection());
+        assert_eq!(
+            bundle_collections(&[&core], &accounts, false).unwrap(),
+            vec![collection]
+        );
+        let other = StakeTerms::Core { asset: owner };
+        assert_eq!(
+            bundle_collections(&[&other], &accounts, false).unwrap_err(),
+            CustomError::InvalidCoreAsset.into()
+        );
+    }
+
+    #[test]
+    fn stake_changes_cannot_drop_unreturnable_deposits() {
+        let (kept, dropped) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut locked = StakedNft::new(dropped);
+        locked.status = NFTStatus::Locked;
+        locked.locked = true;
+        let nfts = Stake::Nft {
+            nfts: vec![StakedNft::new(kept), locked],
+        };
+        assert!(nfts.drops_unreturnable(&StakeTerms::Nft { mints: vec![kept] }));
+        assert!(!nfts.drops_unreturnable(&StakeTerms::Nft { mints: vec![kept, dropped] }));
+
+        let core = Stake::Core {
+            asset: Pubkey::new_unique(),
+            collection: None,
+            status: NFTStatus::Deposited,
+        };
+        assert!(core.drops_unreturnable(&StakeTerms::Sol { lamports: 1 }));
+        assert!(!core.drops_unreturnable(&core.terms()));
+
+        let sol = Stake::Sol {
+            lamports: 1,
+            status: NFTStatus::Deposited,
+        };
+        assert!(!sol.drops_unreturnable(&StakeTerms::Sol { lamports: 2 }));
+    }
+
+    #[test]
+    fn deposits_come_back_undeposited_while_the_opponent_has_nothing_in_escrow() {
+        let (p1, p2, p1_mint, p2_mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let cancelled =
+            deposited_challenge(ChallengeStatus::Cancelled, p1, p2, p1_mint, p2_mint);
+        assert!(cancelled.refund_status(&p1).unwrap() == NFTStatus::Refunded);
+
+        let both_deposited =
+            deposited_challenge(ChallengeStatus::PendingEscrow, p1, p2, p1_mint, p2_mint);
+        assert_eq!(
+            both_deposited.refund_status(&p1).err().unwrap(),
+            CustomError::InvalidChallengeState.into()
+        );
+
+        let p1_deposited = challenge(
+            ChallengeStatus::PendingEscrow,
+            (p1, deposited(p1_mint)),
+            (p2, StakedNft::new(p2_mint)),
+        );
+        assert!(p1_deposited.refund_status(&p1).unwrap() == NFTStatus::NotDeposited);
+    }
+}
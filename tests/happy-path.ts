@@ -3,17 +3,49 @@ import { Program } from "@coral-xyz/anchor";
 import {
   getAccount,
   getAssociatedTokenAddress,
+  getMint,
+  getTransferFeeAmount,
+  getTransferFeeConfig,
   getAssociatedTokenAddressSync,
+  getOrCreateAssociatedTokenAccount,
+  TOKEN_2022_PROGRAM_ID,
 } from "@solana/spl-token";
 import { Keypair } from "@solana/web3.js";
 import { assert } from "chai";
 import { TrinitySolana } from "../target/types/trinity_solana";
-import { mintNftTo } from "./utils";
+import {
+  allowedCollectionAddress,
+  BUBBLEGUM_PROGRAM_ID,
+  challengeAddress,
+  challengeAttestationDigest,
+  challengeDeadlines,
+  Cnft,
+  cnftRoot,
+  cnftTransferArgs,
+  configAddress,
+  coreAssetOwner,
+  escrowAddress,
+  mintCnftTo,
+  mintCoreAssetTo,
+  mintNftTo,
+  mintMetaplexNftTo,
+  mintToken2022NftTo,
+  mintTransferFeeTokensTo,
+  MPL_CORE_PROGRAM_ID,
+  apiNonceAddress,
+  nftTransferAccounts,
+  priceAttestationMessage,
+  solEscrowAddress,
+  SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+  SPL_NOOP_PROGRAM_ID,
+  tokenEscrowAddress,
+  tokenMetadataAccounts,
+} from "./utils";
 
 // Tests for the happy path of the program
 // This test suite will cover the following scenarios:
 // Two users participating in a challenge and p1 winning
-// Service fee is paid to treasury address
+// Service fee is held by the challenge and collected to the treasury address
 describe("happy-path", () => {
   // Set the provider to the default local validator
   const provider = anchor.AnchorProvider.env();
@@ -93,15 +125,13 @@ describe("happy-path", () => {
 
   describe("preparation", () => {
     it("should have set up correctly", async () => {
-      const [configPda] = anchor.web3.PublicKey.findProgramAddressSync(
-        [Buffer.from("config")],
-        program.programId
-      );
+      const configPda = configAddress(program.programId);
       const config = await program.account.config.fetch(configPda);
       assert.equal(config.treasury.toBase58(), treasury.publicKey.toBase58());
       assert.equal(config.chainId.length, 16);
       assert.equal(config.apiSigners.length, 1);
       assert.equal(config.moderatorSigners.length, 4);
+      assert.equal(config.resolutionThreshold, 3);
 
       // Check that the API signers and moderator signers are correct
       assert.equal(
@@ -161,6 +191,287 @@ describe("happy-path", () => {
     });
   });
 
+  describe("adminTransfer", () => {
+    const newAdmin = Keypair.generate();
+
+    it("should not allow proposing an admin if not admin", async () => {
+      try {
+        await program.methods
+          .proposeAdmin(unauthorisedKeypair.publicKey)
+          .accounts({
+            admin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Admin should not have been proposed");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+
+    it("should only allow the nominee to accept", async () => {
+      const configPda = configAddress(program.programId);
+
+      await program.methods.proposeAdmin(newAdmin.publicKey).rpc();
+
+      let config = await program.account.config.fetch(configPda);
+      assert.equal(
+        config.pendingAdmin.toBase58(),
+        newAdmin.publicKey.toBase58()
+      );
+      assert.equal(
+        config.admin.toBase58(),
+        provider.wallet.publicKey.toBase58()
+      );
+
+      try {
+        await program.methods
+          .acceptAdmin()
+          .accounts({
+            newAdmin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Admin should not have been accepted");
+      } catch (error) {
+        assert.include(error.message, "Signer is not the pending admin");
+      }
+
+      await program.methods
+        .acceptAdmin()
+        .accounts({
+          newAdmin: newAdmin.publicKey,
+        })
+        .signers([newAdmin])
+        .rpc();
+
+      config = await program.account.config.fetch(configPda);
+      assert.equal(config.admin.toBase58(), newAdmin.publicKey.toBase58());
+      assert.equal(config.pendingAdmin, null);
+    });
+
+    it("should allow the admin to cancel a transfer", async () => {
+      const configPda = configAddress(program.programId);
+
+      await program.methods
+        .proposeAdmin(unauthorisedKeypair.publicKey)
+        .accounts({
+          admin: newAdmin.publicKey,
+        })
+        .signers([newAdmin])
+        .rpc();
+      await program.methods
+        .cancelAdminTransfer()
+        .accounts({
+          admin: newAdmin.publicKey,
+        })
+        .signers([newAdmin])
+        .rpc();
+
+      const config = await program.account.config.fetch(configPda);
+      assert.equal(config.pendingAdmin, null);
+
+      try {
+        await program.methods
+          .acceptAdmin()
+          .accounts({
+            newAdmin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Admin should not have been accepted");
+      } catch (error) {
+        assert.include(error.message, "No admin transfer is pending");
+      }
+    });
+
+    after(async () => {
+      // Hand the admin role back to the provider wallet
+      await program.methods
+        .proposeAdmin(provider.wallet.publicKey)
+        .accounts({
+          admin: newAdmin.publicKey,
+        })
+        .signers([newAdmin])
+        .rpc();
+      await program.methods
+        .acceptAdmin()
+        .accounts({
+          newAdmin: provider.wallet.publicKey,
+        })
+        .rpc();
+    });
+  });
+
+  describe("migrateConfig", () => {
+    it("should write the current layout version on initialize", async () => {
+      const configPda = configAddress(program.programId);
+      const config = await program.account.config.fetch(configPda);
+      assert.equal(config.version, 1);
+    });
+
+    it("should not migrate a config that is already current", async () => {
+      try {
+        await program.methods.migrateConfig().rpc();
+        assert.fail("Config should not have been migrated");
+      } catch (error) {
+        assert.include(error.message, "Config already has the current layout");
+      }
+    });
+
+    it("should not allow migrating if not admin", async () => {
+      try {
+        await program.methods
+          .migrateConfig()
+          .accounts({ admin: unauthorisedKeypair.publicKey })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Config should not have been migrated");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+  });
+
+  describe("setResolutionThreshold", () => {
+    it("should not allow a threshold above the moderator count", async () => {
+      try {
+        await program.methods.setResolutionThreshold(5).rpc();
+        assert.fail("Threshold should not have been set");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "Resolution threshold must be between 1 and the number of moderators"
+        );
+      }
+    });
+
+    it("should not allow a threshold of zero", async () => {
+      try {
+        await program.methods.setResolutionThreshold(0).rpc();
+        assert.fail("Threshold should not have been set");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "Resolution threshold must be between 1 and the number of moderators"
+        );
+      }
+    });
+
+    it("should not allow setting the threshold if not admin", async () => {
+      try {
+        await program.methods
+          .setResolutionThreshold(2)
+          .accounts({
+            admin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Threshold should not have been set");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+
+    it("should set the threshold", async () => {
+      const configPda = configAddress(program.programId);
+
+      await program.methods.setResolutionThreshold(4).rpc();
+      let config = await program.account.config.fetch(configPda);
+      assert.equal(config.resolutionThreshold, 4);
+
+      // Restore the default used by the rest of the suite
+      await program.methods.setResolutionThreshold(3).rpc();
+      config = await program.account.config.fetch(configPda);
+      assert.equal(config.resolutionThreshold, 3);
+    });
+  });
+
+  describe("setDrawFeePolicy", () => {
+    it("should not allow setting the policy if not admin", async () => {
+      try {
+        await program.methods
+          .setDrawFeePolicy(true)
+          .accounts({
+            admin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Policy should not have been set");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+
+    it("should set the policy", async () => {
+      const configPda = configAddress(program.programId);
+
+      await program.methods.setDrawFeePolicy(true).rpc();
+      let config = await program.account.config.fetch(configPda);
+      assert.equal(config.refundFeesOnDraw, true);
+
+      // Restore the default (fees kept on a draw)
+      await program.methods.setDrawFeePolicy(false).rpc();
+      config = await program.account.config.fetch(configPda);
+      assert.equal(config.refundFeesOnDraw, false);
+    });
+  });
+
+  describe("pause", () => {
+    const PAUSE_CREATION = 1 << 0;
+
+    it("should not allow pausing if not admin", async () => {
+      try {
+        await program.methods
+          .pause(PAUSE_CREATION)
+          .accounts({
+            admin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Program should not have been paused");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+
+    it("should block challenge creation while paused", async () => {
+      const configPda = configAddress(program.programId);
+
+      await program.methods.pause(PAUSE_CREATION).rpc();
+      let config = await program.account.config.fetch(configPda);
+      assert.equal(config.paused, PAUSE_CREATION);
+
+      try {
+        await program.methods
+          .initializeChallenge(
+            new anchor.BN(challengeId),
+            new anchor.BN(p1Fee),
+            new anchor.BN(p2Fee),
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
+          )
+          .accounts({
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            apiSigner: apiSigners[0].publicKey,
+          })
+          .signers([p1, apiSigners[0]])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "This action is currently paused");
+      }
+
+      await program.methods.unpause(PAUSE_CREATION).rpc();
+      config = await program.account.config.fetch(configPda);
+      assert.equal(config.paused, 0);
+    });
+  });
+
   describe("initializeChallenge", () => {
     it("should fail if api signer is not authorized", async () => {
       try {
@@ -169,8 +480,11 @@ describe("happy-path", () => {
             new anchor.BN(challengeId),
             new anchor.BN(p1Fee),
             new anchor.BN(p2Fee),
-            p1Nft.mint,
-            p2Nft.mint
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
           )
           .accounts({
             p1: p1.publicKey,
@@ -195,8 +509,11 @@ describe("happy-path", () => {
             new anchor.BN(challengeId),
             new anchor.BN(p1Fee),
             new anchor.BN(p2Fee),
-            p1Nft.mint,
-            p2Nft.mint
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
           )
           .accounts({
             p1: p1.publicKey,
@@ -207,14 +524,7 @@ describe("happy-path", () => {
           .rpc();
 
         // Check that the challenge was initialized successfully
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
         const challenge = await program.account.challenge.fetch(challengePda);
 
         assert.exists(challenge, "Challenge account should exist");
@@ -225,31 +535,66 @@ describe("happy-path", () => {
         assert.equal(challenge.p2Fee.toString(), p2Fee.toString());
         assert.equal(challenge.p1Paid, false);
         assert.equal(challenge.p2Paid, false);
-        assert.equal(challenge.nft1Mint.toBase58(), p1Nft.mint.toBase58());
-        assert.equal(challenge.nft2Mint.toBase58(), p2Nft.mint.toBase58());
+        assert.equal(challenge.p1Stake.nft.nfts.length, 1);
+        assert.equal(challenge.p2Stake.nft.nfts.length, 1);
+        assert.equal(
+          challenge.p1Stake.nft.nfts[0].mint.toBase58(),
+          p1Nft.mint.toBase58()
+        );
+        assert.equal(
+          challenge.p2Stake.nft.nfts[0].mint.toBase58(),
+          p2Nft.mint.toBase58()
+        );
         assert.equal(
-          JSON.stringify(challenge.nft1Status),
+          JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
           JSON.stringify({ notDeposited: {} })
         );
         assert.equal(
-          JSON.stringify(challenge.nft2Status),
+          JSON.stringify(challenge.p2Stake.nft.nfts[0].status),
           JSON.stringify({ notDeposited: {} })
         );
         assert.equal(
           JSON.stringify(challenge.status),
-          JSON.stringify({ pendingFee: {} })
+          JSON.stringify({ invited: {} })
         );
         assert.equal(challenge.winner, null);
         assert.equal(challenge.moderatorVotes.length, 0);
         assert.equal(challenge.votesForP1, 0);
         assert.equal(challenge.votesForP2, 0);
         assert.equal(challenge.votesToCancel, 0);
+        assert.equal(challenge.resolutionThreshold, 3);
       } catch (error) {
         console.error("Error initializing challenge:", error);
         assert.fail("Failed to initialize challenge");
       }
     });
 
+    it("should fail if both sides stake the same NFT", async () => {
+      try {
+        await program.methods
+          .initializeChallenge(
+            new anchor.BN(challengeId),
+            new anchor.BN(p1Fee),
+            new anchor.BN(p2Fee),
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint, p1Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
+          )
+          .accounts({
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            apiSigner: apiSigners[0].publicKey,
+          })
+          .signers([p1, apiSigners[0]])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "distinct NFTs");
+      }
+    });
+
     it("should fail if challenge already exists", async () => {
       try {
         await program.methods
@@ -257,8 +602,11 @@ describe("happy-path", () => {
             new anchor.BN(challengeId),
             new anchor.BN(p1Fee),
             new anchor.BN(p2Fee),
-            p1Nft.mint,
-            p2Nft.mint
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
           )
           .accounts({
             p1: p1.publicKey,
@@ -280,8 +628,11 @@ describe("happy-path", () => {
             new anchor.BN(challengeId + 1),
             new anchor.BN(0),
             new anchor.BN(0),
-            p1Nft.mint,
-            p2Nft.mint
+            { nft: { mints: [p1Nft.mint] } },
+            { nft: { mints: [p2Nft.mint] } },
+            challengeDeadlines(),
+            { any: {} },
+            null
           )
           .accounts({
             p1: p1.publicKey,
@@ -292,14 +643,7 @@ describe("happy-path", () => {
           .rpc();
 
         // Check that the challenge was initialized successfully
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId + 1).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 1);
         const challenge = await program.account.challenge.fetch(challengePda);
 
         assert.exists(challenge, "Challenge account should exist");
@@ -308,9 +652,10 @@ describe("happy-path", () => {
         assert.equal(challenge.p1Paid, true);
         assert.equal(challenge.p2Paid, true);
 
+        // p2 still has to accept before the challenge proceeds
         assert.equal(
           JSON.stringify(challenge.status),
-          JSON.stringify({ pendingEscrow: {} })
+          JSON.stringify({ invited: {} })
         );
       } catch (error) {
         console.error("Error initializing challenge:", error);
@@ -319,29 +664,109 @@ describe("happy-path", () => {
     });
   });
 
+  describe("acceptChallenge", () => {
+    it("should fail if called by someone other than p2", async () => {
+      try {
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+
+        await program.methods
+          .acceptChallenge(false)
+          .accountsPartial({
+            p2: p1.publicKey,
+            challenge: challengePda,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("Challenge should not have been accepted");
+      } catch (error) {
+        assert.include(error.message, "Invalid payer");
+      }
+    });
+
+    it("should succeed if called by p2", async () => {
+      try {
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+
+        await program.methods
+          .acceptChallenge(false)
+          .accountsPartial({
+            p2: p2.publicKey,
+            challenge: challengePda,
+          })
+          .signers([p2])
+          .rpc();
+
+        const challenge = await program.account.challenge.fetch(challengePda);
+        assert.equal(
+          JSON.stringify(challenge.status),
+          JSON.stringify({ pendingFee: {} })
+        );
+      } catch (error) {
+        console.error("Error accepting challenge:", error);
+        assert.fail("Failed to accept challenge");
+      }
+    });
+  });
+
+  describe("declineChallenge", () => {
+    it("should close the challenge and refund p1", async () => {
+      const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 4);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(challengeId + 4),
+          new anchor.BN(p1Fee),
+          new anchor.BN(p2Fee),
+          { nft: { mints: [p1Nft.mint] } },
+          { nft: { mints: [p2Nft.mint] } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+
+      const p1BalanceBefore = await provider.connection.getBalance(
+        p1.publicKey
+      );
+      const challengeRent = await provider.connection.getBalance(challengePda);
+
+      await program.methods
+        .declineChallenge()
+        .accountsPartial({
+          p2: p2.publicKey,
+          p1: p1.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      const challenge = await program.account.challenge.fetchNullable(
+        challengePda
+      );
+      assert.equal(challenge, null);
+      assert.equal(
+        await provider.connection.getBalance(p1.publicKey),
+        p1BalanceBefore + challengeRent
+      );
+    });
+  });
+
   describe("payServiceFee", () => {
     it("should fail if called by unauthorised user", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
-        const [configPda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [Buffer.from("config")],
-          program.programId
-        );
-        const config = await program.account.config.fetch(configPda);
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
 
         await program.methods
           .payServiceFee()
           .accountsPartial({
             payer: unauthorisedKeypair.publicKey,
             challenge: challengePda,
-            treasury: config.treasury.toBase58(),
           })
           .signers([unauthorisedKeypair])
           .rpc();
@@ -353,26 +778,16 @@ describe("happy-path", () => {
 
     it("should succeed if called by p1", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const challengeBalanceBefore = await provider.connection.getBalance(
+          challengePda
         );
-        const [configPda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [Buffer.from("config")],
-          program.programId
-        );
-        const config = await program.account.config.fetch(configPda);
 
         await program.methods
           .payServiceFee()
           .accountsPartial({
             payer: p1.publicKey,
             challenge: challengePda,
-            treasury: config.treasury.toBase58(),
           })
           .signers([p1])
           .rpc();
@@ -382,15 +797,19 @@ describe("happy-path", () => {
         assert.equal(challenge.p1Paid, true);
         assert.equal(challenge.p2Paid, false);
 
-        // Check fee was transferred to treasury
-        const treasuryBalance = await provider.connection.getBalance(
-          config.treasury
+        // Check fee is held on the challenge rather than sent to the treasury
+        const challengeBalance = await provider.connection.getBalance(
+          challengePda
         );
         assert.equal(
-          treasuryBalance,
-          p1Fee,
-          "Treasury balance should be updated"
+          challengeBalance,
+          challengeBalanceBefore + p1Fee,
+          "Challenge balance should be updated"
         );
+        const treasuryBalance = await provider.connection.getBalance(
+          treasury.publicKey
+        );
+        assert.equal(treasuryBalance, 0, "Treasury should not be paid yet");
       } catch (error) {
         console.error("Error paying service fee:", error);
         assert.fail("Failed to pay service fee");
@@ -399,26 +818,16 @@ describe("happy-path", () => {
 
     it("should succeed if called by p2", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const challengeBalanceBefore = await provider.connection.getBalance(
+          challengePda
         );
-        const [configPda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [Buffer.from("config")],
-          program.programId
-        );
-        const config = await program.account.config.fetch(configPda);
 
         await program.methods
           .payServiceFee()
           .accountsPartial({
             payer: p2.publicKey,
             challenge: challengePda,
-            treasury: config.treasury.toBase58(),
           })
           .signers([p2])
           .rpc();
@@ -432,14 +841,14 @@ describe("happy-path", () => {
           JSON.stringify({ pendingEscrow: {} })
         );
 
-        // Check fee was transferred to treasury
-        const treasuryBalance = await provider.connection.getBalance(
-          config.treasury
+        // Check fee is held on the challenge
+        const challengeBalance = await provider.connection.getBalance(
+          challengePda
         );
         assert.equal(
-          treasuryBalance,
-          p1Fee + p2Fee,
-          "Treasury balance should be updated"
+          challengeBalance,
+          challengeBalanceBefore + p2Fee,
+          "Challenge balance should be updated"
         );
       } catch (error) {
         console.error("Error paying service fee:", error);
@@ -451,25 +860,10 @@ describe("happy-path", () => {
   describe("depositNft", () => {
     it("should fail if called by unauthorised user", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
 
         // Derive the escrow PDA with same seeds used in your program
-        const [escrowTokenAccount] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p1Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
+        const escrowTokenAccount = escrowAddress(program.programId, challengePda, p1Nft.mint);
 
         await program.methods
           .depositNft()
@@ -488,47 +882,95 @@ describe("happy-path", () => {
       }
     });
 
-    it("should succeed if called by p1", async () => {
+    it("should fail if depositing a mint other than the declared one", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const otherNft = await mintNftTo(
+          provider,
+          p1.publicKey,
+          provider.wallet.payer
         );
-
-        // Derive the escrow PDA with same seeds used in your program
-        const [escrowTokenAccount] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p1Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
+        const escrowTokenAccount = escrowAddress(program.programId, challengePda, otherNft.mint);
 
         await program.methods
           .depositNft()
           .accountsPartial({
             depositor: p1.publicKey,
             challenge: challengePda,
-            fromTokenAccount: p1Nft.tokenAccount,
+            fromTokenAccount: otherNft.tokenAccount,
             escrowTokenAccount,
-            nftMint: p1Nft.mint,
+            nftMint: otherNft.mint,
           })
           .signers([p1])
           .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "NFT mint does not match the one declared on the challenge"
+        );
+      }
+    });
+
+    it("should fail if the source account is not owned by the depositor", async () => {
+      try {
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const escrowTokenAccount = escrowAddress(program.programId, challengePda, p2Nft.mint);
+
+        // p2 declared p2Nft, but the source account is an empty p2-mint account owned by p1
+        const p1HeldP2MintAccount = await getOrCreateAssociatedTokenAccount(
+          provider.connection,
+          provider.wallet.payer,
+          p2Nft.mint,
+          p1.publicKey
+        );
+
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p2.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p1HeldP2MintAccount.address,
+            escrowTokenAccount,
+            nftMint: p2Nft.mint,
+          })
+          .signers([p2])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "Source token account is not owned by the depositor"
+        );
+      }
+    });
+
+    it("should succeed if called by p1", async () => {
+      try {
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+
+        // Derive the escrow PDA with same seeds used in your program
+        const escrowTokenAccount = escrowAddress(program.programId, challengePda, p1Nft.mint);
+
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p1.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p1Nft.tokenAccount,
+            escrowTokenAccount,
+            nftMint: p1Nft.mint,
+          })
+          .signers([p1])
+          .rpc();
+
+        // Check that the NFT was deposited successfully
+        const challenge = await program.account.challenge.fetch(challengePda);
+        assert.equal(
+          JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+          JSON.stringify({ deposited: {} })
+        );
 
-        // Check that the NFT was deposited successfully
-        const challenge = await program.account.challenge.fetch(challengePda);
-        assert.equal(
-          JSON.stringify(challenge.nft1Status),
-          JSON.stringify({ deposited: {} })
-        );
-
         const escrow = await getAccount(
           provider.connection,
           escrowTokenAccount
@@ -543,25 +985,10 @@ describe("happy-path", () => {
 
     it("should succeed if called by p2", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
 
         // Derive the escrow PDA with same seeds used in your program
-        const [escrowTokenAccount] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p2Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
+        const escrowTokenAccount = escrowAddress(program.programId, challengePda, p2Nft.mint);
 
         await program.methods
           .depositNft()
@@ -578,7 +1005,7 @@ describe("happy-path", () => {
         // Check that the NFT was deposited successfully
         const challenge = await program.account.challenge.fetch(challengePda);
         assert.equal(
-          JSON.stringify(challenge.nft2Status),
+          JSON.stringify(challenge.p2Stake.nft.nfts[0].status),
           JSON.stringify({ deposited: {} })
         );
 
@@ -604,18 +1031,11 @@ describe("happy-path", () => {
   describe("resolveChallenge", () => {
     it("should fail if called by unauthorised user", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
 
         await program.methods
           .resolveChallenge(
-            1 // 1 for p1, 2 for p2, 3 for cancel
+            1 // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           )
           .accountsPartial({
             moderator: unauthorisedKeypair.publicKey,
@@ -634,14 +1054,7 @@ describe("happy-path", () => {
 
     it("should fail if unknown challenge is in wrong state", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId + 1).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 1);
 
         // check ChallengeId + 1 is not in the right state
         const challenge = await program.account.challenge.fetch(challengePda);
@@ -652,7 +1065,7 @@ describe("happy-path", () => {
 
         await program.methods
           .resolveChallenge(
-            1 // 1 for p1, 2 for p2, 3 for cancel
+            1 // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           )
           .accountsPartial({
             moderator: moderatorSigners[0].publicKey,
@@ -666,16 +1079,27 @@ describe("happy-path", () => {
       }
     });
 
+    it("should not allow a draw on an unready challenge", async () => {
+      try {
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 1);
+
+        await program.methods
+          .resolveChallenge(4) // 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[0].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[0]])
+          .rpc();
+        assert.fail("Challenge should not have been drawn");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+    });
+
     it("should allow cancellation of an unready challenge", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId + 1).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 1);
 
         // Check that the challenge is in the correct state
         let challenge = await program.account.challenge.fetch(challengePda);
@@ -687,7 +1111,7 @@ describe("happy-path", () => {
         const originalStatus = challenge.status;
 
         await program.methods
-          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[0].publicKey,
             challenge: challengePda,
@@ -709,7 +1133,7 @@ describe("happy-path", () => {
 
         // vote to cancel with second moderator
         await program.methods
-          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[1].publicKey,
             challenge: challengePda,
@@ -731,7 +1155,7 @@ describe("happy-path", () => {
 
         //* vote to cancel with third moderator
         await program.methods
-          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[2].publicKey,
             challenge: challengePda,
@@ -758,14 +1182,7 @@ describe("happy-path", () => {
 
     it("should allow p1 to win", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
 
         // Check that the challenge is in the correct state
         let challenge = await program.account.challenge.fetch(challengePda);
@@ -775,7 +1192,7 @@ describe("happy-path", () => {
         );
 
         await program.methods
-          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[0].publicKey,
             challenge: challengePda,
@@ -793,7 +1210,7 @@ describe("happy-path", () => {
         //* Check that a moderator cant vote twice
         try {
           await program.methods
-            .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel
+            .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
             .accountsPartial({
               moderator: moderatorSigners[0].publicKey,
               challenge: challengePda,
@@ -807,7 +1224,7 @@ describe("happy-path", () => {
 
         // vote with moderator 2 and 3
         await program.methods
-          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[1].publicKey,
             challenge: challengePda,
@@ -821,7 +1238,7 @@ describe("happy-path", () => {
         assert.equal(challenge.votesToCancel, 0);
 
         await program.methods
-          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel
+          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
           .accountsPartial({
             moderator: moderatorSigners[2].publicKey,
             challenge: challengePda,
@@ -843,7 +1260,7 @@ describe("happy-path", () => {
         //* Check that it doesn't allow more votes
         try {
           await program.methods
-            .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel
+            .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
             .accountsPartial({
               moderator: moderatorSigners[3].publicKey,
               challenge: challengePda,
@@ -862,38 +1279,277 @@ describe("happy-path", () => {
   });
 
   describe("claimRefundNft", () => {
-    // TODO!
+    const refundChallengeId = challengeId + 5;
+    let p1RefundNft: Awaited<ReturnType<typeof mintNftTo>>;
+    let p2RefundNft: Awaited<ReturnType<typeof mintNftTo>>;
+    let challengePda: anchor.web3.PublicKey;
+    let escrowNft1Account: anchor.web3.PublicKey;
+    let escrowNft2Account: anchor.web3.PublicKey;
+
+    before(async () => {
+      p1RefundNft = await mintNftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      p2RefundNft = await mintNftTo(
+        provider,
+        p2.publicKey,
+        provider.wallet.payer
+      );
+
+      challengePda = challengeAddress(program.programId, p1.publicKey, refundChallengeId);
+      escrowNft1Account = escrowAddress(program.programId, challengePda, p1RefundNft.mint);
+      escrowNft2Account = escrowAddress(program.programId, challengePda, p2RefundNft.mint);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(refundChallengeId),
+          new anchor.BN(p1Fee),
+          new anchor.BN(p2Fee),
+          { nft: { mints: [p1RefundNft.mint] } },
+          { nft: { mints: [p2RefundNft.mint] } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+
+      // p2 accepts, pays and deposits in a single call
+      await program.methods
+        .acceptChallenge(true)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts(
+          nftTransferAccounts([
+            [p2RefundNft.tokenAccount, escrowNft2Account, p2RefundNft.mint],
+          ])
+        )
+        .signers([p2])
+        .rpc();
+
+      await program.methods
+        .payServiceFee()
+        .accountsPartial({
+          payer: p1.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: p1RefundNft.tokenAccount,
+          escrowTokenAccount: escrowNft1Account,
+          nftMint: p1RefundNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+
+      for (let i = 0; i < 3; i++) {
+        await program.methods
+          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[i].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[i]])
+          .rpc();
+      }
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.status),
+        JSON.stringify({ cancelled: {} })
+      );
+    });
+
+    it("should fail if claiming the opponent's NFT", async () => {
+      try {
+        await program.methods
+          .claimRefundNft()
+          .accountsPartial({
+            claimer: p1.publicKey,
+            challenge: challengePda,
+            escrowNftAccount: escrowNft2Account,
+            destinationTokenAccount: p2RefundNft.tokenAccount,
+            nftMint: p2RefundNft.mint,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been refunded");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "NFT mint does not match the one declared on the challenge"
+        );
+      }
+    });
+
+    it("should refund p1", async () => {
+      await program.methods
+        .claimRefundNft()
+        .accountsPartial({
+          claimer: p1.publicKey,
+          challenge: challengePda,
+          escrowNftAccount: escrowNft1Account,
+          destinationTokenAccount: p1RefundNft.tokenAccount,
+          nftMint: p1RefundNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+
+      const destination = await getAccount(
+        provider.connection,
+        p1RefundNft.tokenAccount
+      );
+      assert.equal(destination.amount, BigInt(1));
+      const escrow = await getAccount(provider.connection, escrowNft1Account);
+      assert.equal(escrow.amount, BigInt(0));
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ refunded: {} })
+      );
+    });
+
+    it("should refund p2", async () => {
+      await program.methods
+        .claimRefundNft()
+        .accountsPartial({
+          claimer: p2.publicKey,
+          challenge: challengePda,
+          escrowNftAccount: escrowNft2Account,
+          destinationTokenAccount: p2RefundNft.tokenAccount,
+          nftMint: p2RefundNft.mint,
+        })
+        .signers([p2])
+        .rpc();
+
+      const destination = await getAccount(
+        provider.connection,
+        p2RefundNft.tokenAccount
+      );
+      assert.equal(destination.amount, BigInt(1));
+      const escrow = await getAccount(provider.connection, escrowNft2Account);
+      assert.equal(escrow.amount, BigInt(0));
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p2Stake.nft.nfts[0].status),
+        JSON.stringify({ refunded: {} })
+      );
+    });
+
+    it("should not refund the same NFT twice", async () => {
+      try {
+        await program.methods
+          .claimRefundNft()
+          .accountsPartial({
+            claimer: p1.publicKey,
+            challenge: challengePda,
+            escrowNftAccount: escrowNft1Account,
+            destinationTokenAccount: p1RefundNft.tokenAccount,
+            nftMint: p1RefundNft.mint,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been refunded");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+    });
+
+    it("should refund both fees", async () => {
+      for (const player of [p1, p2]) {
+        const balanceBefore = await provider.connection.getBalance(
+          player.publicKey
+        );
+        await program.methods
+          .claimFeeRefund()
+          .accountsPartial({
+            claimer: player.publicKey,
+            challenge: challengePda,
+          })
+          .signers([player])
+          .rpc();
+        assert.equal(
+          await provider.connection.getBalance(player.publicKey),
+          balanceBefore + p1Fee
+        );
+      }
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.p1FeeRefunded, true);
+      assert.equal(challenge.p2FeeRefunded, true);
+    });
+
+    it("should close the settled challenge and its escrows", async () => {
+      const p1BalanceBefore = await provider.connection.getBalance(
+        p1.publicKey
+      );
+      const p2BalanceBefore = await provider.connection.getBalance(
+        p2.publicKey
+      );
+      const challengeRent = await provider.connection.getBalance(challengePda);
+      const escrowRent = await provider.connection.getBalance(
+        escrowNft1Account
+      );
+
+      await program.methods
+        .closeChallenge()
+        .accountsPartial({
+          challenge: challengePda,
+          rentPayer: p1.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: escrowNft1Account, isWritable: true, isSigner: false },
+          { pubkey: escrowNft2Account, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+
+      assert.equal(
+        await program.account.challenge.fetchNullable(challengePda),
+        null
+      );
+      assert.equal(
+        await provider.connection.getAccountInfo(escrowNft1Account),
+        null
+      );
+      assert.equal(
+        await provider.connection.getAccountInfo(escrowNft2Account),
+        null
+      );
+      assert.equal(
+        await provider.connection.getBalance(p1.publicKey),
+        p1BalanceBefore + challengeRent + escrowRent
+      );
+      assert.equal(
+        await provider.connection.getBalance(p2.publicKey),
+        p2BalanceBefore + escrowRent
+      );
+    });
   });
 
-  describe("claimWinnerNfts", () => {
+  describe("claimWinnings", () => {
     it("should fail if called by non-winner", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
-        const [escrowNft1Account] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p1Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
-        const [escrowNft2Account] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p2Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const escrowNft1Account = escrowAddress(program.programId, challengePda, p1Nft.mint);
+        const escrowNft2Account = escrowAddress(program.programId, challengePda, p2Nft.mint);
         const winnerNft1Account = await getAssociatedTokenAddress(
           p1Nft.mint,
           p2.publicKey
@@ -904,17 +1560,17 @@ describe("happy-path", () => {
         );
 
         await program.methods
-          .claimWinnerNfts()
+          .claimWinnings()
           .accountsPartial({
             claimer: p2.publicKey,
             challenge: challengePda,
-            escrowNft1Account,
-            escrowNft2Account,
-            winnerNft1Account,
-            winnerNft2Account,
-            nft1Mint: p1Nft.mint,
-            nft2Mint: p2Nft.mint,
           })
+          .remainingAccounts(
+            nftTransferAccounts([
+              [escrowNft1Account, winnerNft1Account, p1Nft.mint],
+              [escrowNft2Account, winnerNft2Account, p2Nft.mint],
+            ])
+          )
           .signers([p2])
           .rpc();
         assert.fail("NFT should not have been claimed");
@@ -925,32 +1581,9 @@ describe("happy-path", () => {
 
     it("should succeed if called by winner", async () => {
       try {
-        const [challengePda] = anchor.web3.PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("challenge"),
-            p1.publicKey.toBuffer(),
-            new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
-          ],
-          program.programId
-        );
-        const [escrowNft1Account] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p1Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
-        const [escrowNft2Account] =
-          anchor.web3.PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("escrow"),
-              challengePda.toBuffer(),
-              p2Nft.mint.toBuffer(),
-            ],
-            program.programId
-          );
+        const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+        const escrowNft1Account = escrowAddress(program.programId, challengePda, p1Nft.mint);
+        const escrowNft2Account = escrowAddress(program.programId, challengePda, p2Nft.mint);
         const winnerNft1Account = getAssociatedTokenAddressSync(
           p1Nft.mint,
           p1.publicKey
@@ -961,17 +1594,17 @@ describe("happy-path", () => {
         );
 
         await program.methods
-          .claimWinnerNfts()
+          .claimWinnings()
           .accountsPartial({
             claimer: p1.publicKey,
             challenge: challengePda,
-            escrowNft1Account,
-            escrowNft2Account,
-            nft1Mint: p1Nft.mint,
-            nft2Mint: p2Nft.mint,
-            winnerNft1Account,
-            winnerNft2Account,
           })
+          .remainingAccounts(
+            nftTransferAccounts([
+              [escrowNft1Account, winnerNft1Account, p1Nft.mint],
+              [escrowNft2Account, winnerNft2Account, p2Nft.mint],
+            ])
+          )
           .signers([p1])
           .rpc();
 
@@ -1002,11 +1635,11 @@ describe("happy-path", () => {
         // Check that the NFT statuses are updated to claimed
         const challenge = await program.account.challenge.fetch(challengePda);
         assert.equal(
-          JSON.stringify(challenge.nft1Status),
+          JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
           JSON.stringify({ claimed: {} })
         );
         assert.equal(
-          JSON.stringify(challenge.nft2Status),
+          JSON.stringify(challenge.p2Stake.nft.nfts[0].status),
           JSON.stringify({ claimed: {} })
         );
       } catch (error) {
@@ -1053,7 +1686,7 @@ describe("happy-path", () => {
     //         );
 
     //         await program.methods
-    //           .claimWinnerNfts()
+    //           .claimWinnings()
     //           .accountsPartial({
     //             claimer: p1.publicKey,
     //             challenge: challengePda,
@@ -1073,4 +1706,2408 @@ describe("happy-path", () => {
     //       }
     //     });
   });
+
+  describe("collectFees", () => {
+    it("should send the held fees to the treasury", async () => {
+      const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId);
+      const challengeBalanceBefore = await provider.connection.getBalance(
+        challengePda
+      );
+
+      await program.methods
+        .collectFees()
+        .accountsPartial({
+          challenge: challengePda,
+          treasury: treasury.publicKey,
+        })
+        .rpc();
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.feesCollected, true);
+      assert.equal(
+        await provider.connection.getBalance(treasury.publicKey),
+        p1Fee + p2Fee
+      );
+      assert.equal(
+        await provider.connection.getBalance(challengePda),
+        challengeBalanceBefore - p1Fee - p2Fee
+      );
+
+      try {
+        await program.methods
+          .collectFees()
+          .accountsPartial({
+            challenge: challengePda,
+            treasury: treasury.publicKey,
+          })
+          .rpc();
+        assert.fail("Fees should not have been collected twice");
+      } catch (error) {
+        assert.include(error.message, "Fees have already been collected");
+      }
+    });
+  });
+
+  describe("expireChallenge", () => {
+    it("should not expire a challenge before its deadline", async () => {
+      const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 2);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(challengeId + 2),
+          new anchor.BN(p1Fee),
+          new anchor.BN(p2Fee),
+          { nft: { mints: [p1Nft.mint] } },
+          { nft: { mints: [p2Nft.mint] } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+
+      try {
+        await program.methods
+          .expireChallenge()
+          .accountsPartial({
+            challenge: challengePda,
+          })
+          .rpc();
+        assert.fail("Challenge should not have expired");
+      } catch (error) {
+        assert.include(error.message, "Challenge deadline has not passed yet");
+      }
+    });
+
+    it("should let anyone expire a challenge after its deadline", async () => {
+      const challengePda = challengeAddress(program.programId, p1.publicKey, challengeId + 3);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(challengeId + 3),
+          new anchor.BN(p1Fee),
+          new anchor.BN(p2Fee),
+          { nft: { mints: [p1Nft.mint] } },
+          { nft: { mints: [p2Nft.mint] } },
+          challengeDeadlines(2),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+
+      // Wait for the fee deadline to pass
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      await program.methods
+        .expireChallenge()
+        .accountsPartial({
+          challenge: challengePda,
+        })
+        .rpc();
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.cancelled, true);
+      assert.equal(
+        JSON.stringify(challenge.status),
+        JSON.stringify({ cancelled: {} })
+      );
+    });
+  });
+
+  describe("solStake", () => {
+    const solChallengeId = challengeId + 6;
+    const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2);
+    let challengePda: anchor.web3.PublicKey;
+    let p1SolEscrow: anchor.web3.PublicKey;
+    let p2SolEscrow: anchor.web3.PublicKey;
+
+    before(async () => {
+      challengePda = challengeAddress(program.programId, p1.publicKey, solChallengeId);
+      p1SolEscrow = solEscrowAddress(program.programId, challengePda, p1.publicKey);
+      p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(solChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { sol: { lamports: stake } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+    });
+
+    it("should not accept an NFT deposit for a SOL stake", async () => {
+      const escrowTokenAccount = escrowAddress(program.programId, challengePda, p1Nft.mint);
+      try {
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p1.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p1Nft.tokenAccount,
+            escrowTokenAccount,
+            nftMint: p1Nft.mint,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "Stake type does not match");
+      }
+    });
+
+    it("should escrow both SOL stakes and become ready", async () => {
+      for (const player of [p1, p2]) {
+        await program.methods
+          .depositSol()
+          .accountsPartial({
+            depositor: player.publicKey,
+            challenge: challengePda,
+          })
+          .signers([player])
+          .rpc();
+      }
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.sol.status),
+        JSON.stringify({ deposited: {} })
+      );
+      assert.equal(
+        JSON.stringify(challenge.status),
+        JSON.stringify({ ready: {} })
+      );
+      const escrowRent =
+        await provider.connection.getMinimumBalanceForRentExemption(8 + 1);
+      assert.equal(
+        await provider.connection.getBalance(p2SolEscrow),
+        escrowRent + stake.toNumber()
+      );
+    });
+
+    it("should pay both stakes to the winner and close the escrows", async () => {
+      for (let i = 0; i < 3; i++) {
+        await program.methods
+          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[i].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[i]])
+          .rpc();
+      }
+
+      const p1BalanceBefore = await provider.connection.getBalance(
+        p1.publicKey
+      );
+      await program.methods
+        .claimWinnings()
+        .accountsPartial({
+          claimer: p1.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts([
+          { pubkey: p1SolEscrow, isWritable: true, isSigner: false },
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p1])
+        .rpc();
+      // p1 also paid the transaction fee
+      assert.isAbove(
+        await provider.connection.getBalance(p1.publicKey),
+        p1BalanceBefore + stake.toNumber() * 2 - 10_000
+      );
+
+      await program.methods
+        .collectFees()
+        .accountsPartial({
+          challenge: challengePda,
+          treasury: treasury.publicKey,
+        })
+        .rpc();
+      await program.methods
+        .closeChallenge()
+        .accountsPartial({
+          challenge: challengePda,
+          rentPayer: p1.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: p1SolEscrow, isWritable: true, isSigner: false },
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+
+      assert.equal(await provider.connection.getAccountInfo(p1SolEscrow), null);
+      assert.equal(await provider.connection.getAccountInfo(p2SolEscrow), null);
+    });
+  });
+
+  describe("asymmetricStake", () => {
+    it("should pay an NFT and a SOL stake to the winner", async () => {
+      const asymmetricChallengeId = challengeId + 7;
+      const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4);
+      const p1StakeNft = await mintNftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      const challengePda = challengeAddress(program.programId, p1.publicKey, asymmetricChallengeId);
+      const escrowNftAccount = escrowAddress(program.programId, challengePda, p1StakeNft.mint);
+      const p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(asymmetricChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [p1StakeNft.mint] } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: p1StakeNft.tokenAccount,
+          escrowTokenAccount: escrowNftAccount,
+          nftMint: p1StakeNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      for (let i = 0; i < 3; i++) {
+        await program.methods
+          .resolveChallenge(2) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[i].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[i]])
+          .rpc();
+      }
+
+      const winnerNftAccount = getAssociatedTokenAddressSync(
+        p1StakeNft.mint,
+        p2.publicKey
+      );
+      const escrowBalanceBefore = await provider.connection.getBalance(
+        p2SolEscrow
+      );
+      await program.methods
+        .claimWinnings()
+        .accountsPartial({
+          claimer: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts([
+          ...nftTransferAccounts([
+            [escrowNftAccount, winnerNftAccount, p1StakeNft.mint],
+          ]),
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p2])
+        .rpc();
+
+      const winnerNft = await getAccount(provider.connection, winnerNftAccount);
+      assert.equal(winnerNft.amount, BigInt(1));
+      assert.equal(
+        await provider.connection.getBalance(p2SolEscrow),
+        escrowBalanceBefore - stake.toNumber()
+      );
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ claimed: {} })
+      );
+      assert.equal(
+        JSON.stringify(challenge.p2Stake.sol.status),
+        JSON.stringify({ claimed: {} })
+      );
+    });
+  });
+
+  describe("token2022", () => {
+    const token2022ChallengeId = challengeId + 8;
+    let p1Token2022Nft: Awaited<ReturnType<typeof mintToken2022NftTo>>;
+    let p2NonTransferableNft: Awaited<ReturnType<typeof mintToken2022NftTo>>;
+    let challengePda: anchor.web3.PublicKey;
+
+    const escrowFor = (mint: anchor.web3.PublicKey) =>
+      escrowAddress(program.programId, challengePda, mint);
+
+    before(async () => {
+      p1Token2022Nft = await mintToken2022NftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      p2NonTransferableNft = await mintToken2022NftTo(
+        provider,
+        p2.publicKey,
+        provider.wallet.payer,
+        true
+      );
+      challengePda = challengeAddress(program.programId, p1.publicKey, token2022ChallengeId);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(token2022ChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [p1Token2022Nft.mint] } },
+          { nft: { mints: [p2NonTransferableNft.mint] } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+    });
+
+    it("should escrow a Token-2022 NFT", async () => {
+      const escrowTokenAccount = escrowFor(p1Token2022Nft.mint);
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: p1Token2022Nft.tokenAccount,
+          escrowTokenAccount,
+          nftMint: p1Token2022Nft.mint,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([p1])
+        .rpc();
+
+      const escrow = await getAccount(
+        provider.connection,
+        escrowTokenAccount,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      assert.equal(escrow.owner.toBase58(), challengePda.toBase58());
+      assert.equal(escrow.amount, BigInt(1));
+    });
+
+    it("should reject a non-transferable NFT", async () => {
+      try {
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p2.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p2NonTransferableNft.tokenAccount,
+            escrowTokenAccount: escrowFor(p2NonTransferableNft.mint),
+            nftMint: p2NonTransferableNft.mint,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([p2])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "Non-transferable mints cannot be escrowed");
+      }
+    });
+
+    it("should reject an NFT with a transfer fee", async () => {
+      const feeChallengeId = challengeId + 28;
+      const feeNft = await mintTransferFeeTokensTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer,
+        100,
+        1
+      );
+      const feeChallengePda = challengeAddress(program.programId, p1.publicKey, feeChallengeId);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(feeChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [feeNft.mint] } },
+          { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4) } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: feeChallengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      try {
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p1.publicKey,
+            challenge: feeChallengePda,
+            fromTokenAccount: feeNft.tokenAccount,
+            escrowTokenAccount: escrowAddress(
+              program.programId,
+              feeChallengePda,
+              feeNft.mint
+            ),
+            nftMint: feeNft.mint,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "NFT mints with a transfer fee cannot be escrowed");
+      }
+    });
+  });
+
+  describe("transferFeeStake", () => {
+    const feeChallengeId = challengeId + 21;
+    const stakeAmount = 1000;
+    const feeBasisPoints = 100; // 1%
+    const solStake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 10);
+    let feeMint: Awaited<ReturnType<typeof mintTransferFeeTokensTo>>;
+    let challengePda: anchor.web3.PublicKey;
+    let p1TokenEscrow: anchor.web3.PublicKey;
+    let p2SolEscrow: anchor.web3.PublicKey;
+
+    before(async () => {
+      feeMint = await mintTransferFeeTokensTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer,
+        feeBasisPoints,
+        stakeAmount
+      );
+      challengePda = challengeAddress(program.programId, p1.publicKey, feeChallengeId);
+      p1TokenEscrow = tokenEscrowAddress(program.programId, challengePda, p1.publicKey);
+      p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(feeChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { token: { mint: feeMint.mint, amount: new anchor.BN(stakeAmount) } },
+          { sol: { lamports: solStake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+    });
+
+    it("should record the amount received after the transfer fee", async () => {
+      await program.methods
+        .depositTokens()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          mint: feeMint.mint,
+          fromTokenAccount: feeMint.tokenAccount,
+          escrowTokenAccount: p1TokenEscrow,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      const fee = (stakeAmount * feeBasisPoints) / 10_000;
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.p1Stake.token.amount.toNumber(), stakeAmount);
+      assert.equal(challenge.p1Stake.token.escrowed.toNumber(), stakeAmount - fee);
+      assert.equal(
+        JSON.stringify(challenge.status),
+        JSON.stringify({ ready: {} })
+      );
+
+      // The withheld fee was harvested to the mint so the escrow can be closed
+      const escrow = await getAccount(
+        provider.connection,
+        p1TokenEscrow,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      assert.equal(escrow.amount, BigInt(stakeAmount - fee));
+      assert.equal(getTransferFeeAmount(escrow).withheldAmount, BigInt(0));
+      const mint = await getMint(
+        provider.connection,
+        feeMint.mint,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      assert.equal(getTransferFeeConfig(mint).withheldAmount, BigInt(fee));
+    });
+
+    it("should pay out the received amount and close the escrow", async () => {
+      for (let i = 0; i < 3; i++) {
+        await program.methods
+          .resolveChallenge(1) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[i].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[i]])
+          .rpc();
+      }
+
+      await program.methods
+        .claimWinnings()
+        .accountsPartial({
+          claimer: p1.publicKey,
+          challenge: challengePda,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          { pubkey: p1TokenEscrow, isWritable: true, isSigner: false },
+          { pubkey: feeMint.tokenAccount, isWritable: true, isSigner: false },
+          { pubkey: feeMint.mint, isWritable: false, isSigner: false },
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p1])
+        .rpc();
+
+      // The payout is charged the transfer fee again, withheld in p1's account
+      const received = stakeAmount - (stakeAmount * feeBasisPoints) / 10_000;
+      const payoutFee = Math.ceil((received * feeBasisPoints) / 10_000);
+      const p1Account = await getAccount(
+        provider.connection,
+        feeMint.tokenAccount,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      assert.equal(p1Account.amount, BigInt(received - payoutFee));
+      const escrow = await getAccount(
+        provider.connection,
+        p1TokenEscrow,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      assert.equal(escrow.amount, BigInt(0));
+
+      await program.methods
+        .collectFees()
+        .accountsPartial({
+          challenge: challengePda,
+          treasury: treasury.publicKey,
+        })
+        .rpc();
+      await program.methods
+        .closeChallenge()
+        .accountsPartial({
+          challenge: challengePda,
+          rentPayer: p1.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: p1TokenEscrow, isWritable: true, isSigner: false },
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+
+      assert.equal(await provider.connection.getAccountInfo(p1TokenEscrow), null);
+    });
+  });
+
+  // Votes with enough moderators to reach the resolution threshold
+  const resolve = async (challengePda: anchor.web3.PublicKey, vote: number) => {
+    for (let i = 0; i < 3; i++) {
+      await program.methods
+        .resolveChallenge(vote) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+        .accountsPartial({
+          moderator: moderatorSigners[i].publicKey,
+          challenge: challengePda,
+        })
+        .signers([moderatorSigners[i]])
+        .rpc();
+    }
+  };
+
+  describe("pnft", () => {
+    const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4);
+
+    // Mints p1 a pNFT and creates a challenge staking it against p2's SOL
+    const setupPnftChallenge = async (pnftChallengeId: number) => {
+      const p1Pnft = await mintMetaplexNftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer,
+        true
+      );
+      const challengePda = challengeAddress(program.programId, p1.publicKey, pnftChallengeId);
+      const escrowTokenAccount = getAssociatedTokenAddressSync(
+        p1Pnft.mint,
+        challengePda,
+        true
+      );
+      const playerTokenAccount = getAssociatedTokenAddressSync(
+        p1Pnft.mint,
+        p1.publicKey
+      );
+      const {
+        metadata,
+        edition,
+        tokenRecords: [playerTokenRecord, escrowTokenRecord],
+      } = tokenMetadataAccounts(p1Pnft.mint, [
+        playerTokenAccount,
+        escrowTokenAccount,
+      ]);
+      const pnftAccounts = {
+        player: p1.publicKey,
+        challenge: challengePda,
+        nftMint: p1Pnft.mint,
+        playerTokenAccount,
+        escrowTokenAccount,
+        metadata,
+        edition,
+        playerTokenRecord,
+        escrowTokenRecord,
+        authorizationRulesProgram: null,
+        authorizationRules: null,
+      };
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(pnftChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [p1Pnft.mint] } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      return { p1Pnft, challengePda, pnftAccounts };
+    };
+    const depositBoth = async (
+      challengePda: anchor.web3.PublicKey,
+      pnftAccounts: any
+    ) => {
+      await program.methods
+        .depositPnft()
+        .accountsPartial(pnftAccounts)
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+    };
+    it("should escrow a pNFT and pay it to the winner", async () => {
+      const { p1Pnft, challengePda, pnftAccounts } = await setupPnftChallenge(
+        challengeId + 9
+      );
+
+      try {
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p1.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p1Pnft.tokenAccount,
+            nftMint: p1Pnft.mint,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("pNFT should not move with a plain token transfer");
+      } catch (error) {
+        // Token Program AccountFrozen
+        assert.include(error.message, "custom program error: 0x11");
+      }
+
+      await depositBoth(challengePda, pnftAccounts);
+      const escrow = await getAccount(
+        provider.connection,
+        pnftAccounts.escrowTokenAccount
+      );
+      assert.equal(escrow.amount, BigInt(1));
+
+      await resolve(challengePda, 1);
+      await program.methods
+        .claimWinnerPnft()
+        .accountsPartial(pnftAccounts)
+        .signers([p1])
+        .rpc();
+
+      const winnerNft = await getAccount(
+        provider.connection,
+        pnftAccounts.playerTokenAccount
+      );
+      assert.equal(winnerNft.amount, BigInt(1));
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.isTrue(challenge.p1Stake.nft.nfts[0].programmable);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ claimed: {} })
+      );
+    });
+
+    it("should refund a pNFT after a cancellation", async () => {
+      const { challengePda, pnftAccounts } = await setupPnftChallenge(
+        challengeId + 22
+      );
+      await depositBoth(challengePda, pnftAccounts);
+      await resolve(challengePda, 3);
+
+      try {
+        await program.methods
+          .claimWinnerPnft()
+          .accountsPartial(pnftAccounts)
+          .signers([p1])
+          .rpc();
+        assert.fail("pNFT should not have been claimed");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+
+      await program.methods
+        .claimRefundPnft()
+        .accountsPartial(pnftAccounts)
+        .signers([p1])
+        .rpc();
+
+      const refunded = await getAccount(
+        provider.connection,
+        pnftAccounts.playerTokenAccount
+      );
+      assert.equal(refunded.amount, BigInt(1));
+      // Token Metadata re-freezes the pNFT in its owner's wallet
+      assert.isTrue(refunded.isFrozen);
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ refunded: {} })
+      );
+    });
+
+    it("should let p1 withdraw a pNFT while p2 has nothing in escrow", async () => {
+      const { challengePda, pnftAccounts } = await setupPnftChallenge(
+        challengeId + 23
+      );
+      await program.methods
+        .depositPnft()
+        .accountsPartial(pnftAccounts)
+        .signers([p1])
+        .rpc();
+
+      await program.methods
+        .claimRefundPnft()
+        .accountsPartial(pnftAccounts)
+        .signers([p1])
+        .rpc();
+
+      const withdrawn = await getAccount(
+        provider.connection,
+        pnftAccounts.playerTokenAccount
+      );
+      assert.equal(withdrawn.amount, BigInt(1));
+      let challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ notDeposited: {} })
+      );
+
+      // A withdrawn pNFT can be deposited again
+      await depositBoth(challengePda, pnftAccounts);
+      challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ ready: {} }));
+    });
+  });
+
+  describe("cnft", () => {
+    const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4);
+
+    // Mints p1 a cNFT and creates a challenge staking it against p2's SOL
+    const setupCnftChallenge = async (cnftChallengeId: number) => {
+      const cnft = await mintCnftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      const challengePda = challengeAddress(program.programId, p1.publicKey, cnftChallengeId);
+      const cnftAccounts = {
+        player: p1.publicKey,
+        challenge: challengePda,
+        treeConfig: cnft.treeConfig,
+        merkleTree: cnft.merkleTree,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        bubblegumProgram: BUBBLEGUM_PROGRAM_ID,
+      };
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(cnftChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { cnft: { merkleTree: cnft.merkleTree, assetId: cnft.assetId } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      return { cnft, challengePda, cnftAccounts };
+    };
+    const depositBoth = async (cnft: Cnft, cnftAccounts: any) => {
+      const { leaf, proof } = await cnftTransferArgs(provider, cnft);
+      await program.methods
+        .depositCnft(leaf)
+        .accountsPartial(cnftAccounts)
+        .remainingAccounts(proof)
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: cnftAccounts.challenge,
+        })
+        .signers([p2])
+        .rpc();
+    };
+    const assertLeafOwner = async (cnft: Cnft, owner: anchor.web3.PublicKey) => {
+      const { leaf } = await cnftTransferArgs(provider, cnft);
+      assert.deepEqual(leaf.root, cnftRoot(cnft, owner));
+    };
+
+    it("should reject a cNFT from another tree", async () => {
+      const { challengePda, cnftAccounts } = await setupCnftChallenge(
+        challengeId + 10
+      );
+      const otherTree = Keypair.generate().publicKey;
+
+      try {
+        await program.methods
+          .depositCnft({
+            root: new Array(32).fill(0),
+            dataHash: new Array(32).fill(0),
+            creatorHash: new Array(32).fill(0),
+            nonce: new anchor.BN(0),
+            index: 0,
+          })
+          .accountsPartial({
+            ...cnftAccounts,
+            treeConfig: anchor.web3.PublicKey.findProgramAddressSync(
+              [otherTree.toBuffer()],
+              BUBBLEGUM_PROGRAM_ID
+            )[0],
+            merkleTree: otherTree,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("cNFT should not have been deposited");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "Compressed NFT does not match the one declared on the challenge"
+        );
+      }
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.cnft.status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+
+    it("should escrow a cNFT and pay it to the winner", async () => {
+      const { cnft, challengePda, cnftAccounts } = await setupCnftChallenge(
+        challengeId + 24
+      );
+      await assertLeafOwner(cnft, p1.publicKey);
+
+      await depositBoth(cnft, cnftAccounts);
+      await assertLeafOwner(cnft, challengePda);
+      let challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ ready: {} }));
+
+      await resolve(challengePda, 1);
+      const { leaf, proof } = await cnftTransferArgs(provider, cnft);
+      await program.methods
+        .claimWinnerCnft(leaf)
+        .accountsPartial(cnftAccounts)
+        .remainingAccounts(proof)
+        .signers([p1])
+        .rpc();
+
+      await assertLeafOwner(cnft, p1.publicKey);
+      challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.cnft.status),
+        JSON.stringify({ claimed: {} })
+      );
+    });
+
+    it("should refund a cNFT after a cancellation", async () => {
+      const { cnft, challengePda, cnftAccounts } = await setupCnftChallenge(
+        challengeId + 25
+      );
+      await depositBoth(cnft, cnftAccounts);
+      await resolve(challengePda, 3);
+
+      const { leaf, proof } = await cnftTransferArgs(provider, cnft);
+      try {
+        await program.methods
+          .claimWinnerCnft(leaf)
+          .accountsPartial(cnftAccounts)
+          .remainingAccounts(proof)
+          .signers([p1])
+          .rpc();
+        assert.fail("cNFT should not have been claimed");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+
+      await program.methods
+        .claimRefundCnft(leaf)
+        .accountsPartial(cnftAccounts)
+        .remainingAccounts(proof)
+        .signers([p1])
+        .rpc();
+
+      await assertLeafOwner(cnft, p1.publicKey);
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.cnft.status),
+        JSON.stringify({ refunded: {} })
+      );
+    });
+  });
+
+  describe("core", () => {
+    const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4);
+
+    // Mints p1 a Core asset and creates a challenge staking it against p2's SOL
+    const setupCoreChallenge = async (coreChallengeId: number) => {
+      const asset = await mintCoreAssetTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      const challengePda = challengeAddress(program.programId, p1.publicKey, coreChallengeId);
+      const coreAccounts = {
+        player: p1.publicKey,
+        challenge: challengePda,
+        asset,
+        collection: null,
+        allowedCollection: null,
+        coreProgram: MPL_CORE_PROGRAM_ID,
+      };
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(coreChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { core: { asset } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      await program.methods
+        .depositCore()
+        .accountsPartial(coreAccounts)
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      return { asset, challengePda, coreAccounts };
+    };
+
+    it("should only escrow accounts owned by mpl-core", async () => {
+      const coreChallengeId = challengeId + 11;
+      const asset = Keypair.generate().publicKey;
+      const challengePda = challengeAddress(program.programId, p1.publicKey, coreChallengeId);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(coreChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { core: { asset } },
+          { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      try {
+        await program.methods
+          .depositCore()
+          .accountsPartial({
+            player: p1.publicKey,
+            challenge: challengePda,
+            asset,
+            collection: null,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("Asset should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "ConstraintOwner");
+      }
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.p1Stake.core.asset.toBase58(), asset.toBase58());
+      assert.isNull(challenge.p1Stake.core.collection);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.core.status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+
+    it("should escrow a Core asset and pay it to the winner", async () => {
+      const { asset, challengePda, coreAccounts } = await setupCoreChallenge(
+        challengeId + 26
+      );
+      assert.equal(
+        (await coreAssetOwner(provider, asset)).toBase58(),
+        challengePda.toBase58()
+      );
+      let challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ ready: {} }));
+      assert.isNull(challenge.p1Stake.core.collection);
+
+      await resolve(challengePda, 1);
+      await program.methods
+        .claimWinnerCore()
+        .accountsPartial(coreAccounts)
+        .signers([p1])
+        .rpc();
+
+      assert.equal(
+        (await coreAssetOwner(provider, asset)).toBase58(),
+        p1.publicKey.toBase58()
+      );
+      challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.core.status),
+        JSON.stringify({ claimed: {} })
+      );
+    });
+
+    it("should refund a Core asset after a cancellation", async () => {
+      const { asset, challengePda, coreAccounts } = await setupCoreChallenge(
+        challengeId + 27
+      );
+      await resolve(challengePda, 3);
+
+      try {
+        await program.methods
+          .claimWinnerCore()
+          .accountsPartial(coreAccounts)
+          .signers([p1])
+          .rpc();
+        assert.fail("Asset should not have been claimed");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+
+      await program.methods
+        .claimRefundCore()
+        .accountsPartial(coreAccounts)
+        .signers([p1])
+        .rpc();
+
+      assert.equal(
+        (await coreAssetOwner(provider, asset)).toBase58(),
+        p1.publicKey.toBase58()
+      );
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.core.status),
+        JSON.stringify({ refunded: {} })
+      );
+    });
+  });
+
+  describe("lockNft", () => {
+    it("should lock an NFT in place and pay it to the winner", async () => {
+      const lockChallengeId = challengeId + 12;
+      const stake = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4);
+      const p1LockedNft = await mintMetaplexNftTo(
+        provider,
+        p1.publicKey,
+        provider.wallet.payer
+      );
+      const { edition } = tokenMetadataAccounts(p1LockedNft.mint);
+      const challengePda = challengeAddress(program.programId, p1.publicKey, lockChallengeId);
+      const p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(lockChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [p1LockedNft.mint] } },
+          { sol: { lamports: stake } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .lockNft()
+        .accountsPartial({
+          player: p1.publicKey,
+          challenge: challengePda,
+          nftMint: p1LockedNft.mint,
+          playerTokenAccount: p1LockedNft.tokenAccount,
+          edition,
+        })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({
+          depositor: p2.publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2])
+        .rpc();
+
+      const locked = await getAccount(
+        provider.connection,
+        p1LockedNft.tokenAccount
+      );
+      assert.isTrue(locked.isFrozen);
+      assert.equal(locked.amount, BigInt(1));
+      assert.equal(locked.delegate.toBase58(), challengePda.toBase58());
+      let challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ locked: {} })
+      );
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ ready: {} }));
+
+      for (let i = 0; i < 3; i++) {
+        await program.methods
+          .resolveChallenge(2) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[i].publicKey,
+            challenge: challengePda,
+          })
+          .signers([moderatorSigners[i]])
+          .rpc();
+      }
+
+      await program.methods
+        .claimLockedNft()
+        .accountsPartial({
+          winner: p2.publicKey,
+          challenge: challengePda,
+          nftMint: p1LockedNft.mint,
+          lockedTokenAccount: p1LockedNft.tokenAccount,
+          edition,
+        })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .claimWinnings()
+        .accountsPartial({
+          claimer: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts([
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p2])
+        .rpc();
+
+      const winnerNft = await getAccount(
+        provider.connection,
+        getAssociatedTokenAddressSync(p1LockedNft.mint, p2.publicKey)
+      );
+      assert.equal(winnerNft.amount, BigInt(1));
+      challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ claimed: {} })
+      );
+    });
+  });
+
+  describe("collectionAllowlist", () => {
+    const collection = Keypair.generate().publicKey;
+    const allowedCollectionPda = allowedCollectionAddress(program.programId, collection);
+    const configPda = configAddress(program.programId);
+
+    it("should not allow adding a collection if not admin", async () => {
+      try {
+        await program.methods
+          .addAllowedCollection(collection, 0)
+          .accounts({
+            admin: unauthorisedKeypair.publicKey,
+          })
+          .signers([unauthorisedKeypair])
+          .rpc();
+        assert.fail("Collection should not have been added");
+      } catch (error) {
+        assert.include(error.message, "Only the admin can perform this action");
+      }
+    });
+
+    it("should add, update and remove a collection", async () => {
+      await program.methods.addAllowedCollection(collection, 2).rpc();
+      let allowedCollection = await program.account.allowedCollection.fetch(
+        allowedCollectionPda
+      );
+      assert.equal(allowedCollection.collection.toBase58(), collection.toBase58());
+      assert.equal(allowedCollection.maxNftsPerStake, 2);
+
+      await program.methods
+        .setCollectionParams(0)
+        .accountsPartial({ allowedCollection: allowedCollectionPda })
+        .rpc();
+      allowedCollection = await program.account.allowedCollection.fetch(
+        allowedCollectionPda
+      );
+      assert.equal(allowedCollection.maxNftsPerStake, 0);
+
+      await program.methods
+        .removeAllowedCollection()
+        .accountsPartial({ allowedCollection: allowedCollectionPda })
+        .rpc();
+      assert.isNull(
+        await provider.connection.getAccountInfo(allowedCollectionPda)
+      );
+    });
+
+    it("should require collection accounts while enforced", async () => {
+      await program.methods.setCollectionAllowlistEnforced(true).rpc();
+      let config = await program.account.config.fetch(configPda);
+      assert.isTrue(config.enforceCollectionAllowlist);
+
+      try {
+        await program.methods
+          .initializeChallenge(
+            new anchor.BN(challengeId + 13),
+            new anchor.BN(0),
+            new anchor.BN(0),
+            { nft: { mints: [p1Nft.mint] } },
+            { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } },
+            challengeDeadlines(),
+            { any: {} },
+            null
+          )
+          .accounts({
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            apiSigner: apiSigners[0].publicKey,
+          })
+          .signers([p1, apiSigners[0]])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "InvalidRemainingAccounts");
+      }
+
+      await program.methods.setCollectionAllowlistEnforced(false).rpc();
+      config = await program.account.config.fetch(configPda);
+      assert.isFalse(config.enforceCollectionAllowlist);
+    });
+
+    it("should check cNFT and Core stakes at creation while enforced", async () => {
+      await program.methods.setCollectionAllowlistEnforced(true).rpc();
+      const initialize = (p1Stake: any) =>
+        program.methods
+          .initializeChallenge(
+            new anchor.BN(challengeId + 13),
+            new anchor.BN(0),
+            new anchor.BN(0),
+            p1Stake,
+            { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } },
+            challengeDeadlines(),
+            { any: {} },
+            null
+          )
+          .accounts({
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            apiSigner: apiSigners[0].publicKey,
+          })
+          .signers([p1, apiSigners[0]]);
+
+      try {
+        await initialize({
+          cnft: {
+            merkleTree: Keypair.generate().publicKey,
+            assetId: Keypair.generate().publicKey,
+          },
+        }).rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(
+          error.message,
+          "Compressed NFTs can't be staked while the collection allowlist is enforced"
+        );
+      }
+
+      try {
+        await initialize({ core: { asset: Keypair.generate().publicKey } }).rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "InvalidRemainingAccounts");
+      }
+
+      await program.methods.setCollectionAllowlistEnforced(false).rpc();
+    });
+
+    it("should check deposits made after the allowlist is enforced", async () => {
+      const allowlistChallengeId = challengeId + 29;
+      const p1Nft = await mintMetaplexNftTo(provider, p1.publicKey, provider.wallet.payer);
+      const p2Nft = await mintNftTo(provider, p2.publicKey, provider.wallet.payer);
+      const challengePda = challengeAddress(program.programId, p1.publicKey, allowlistChallengeId);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(allowlistChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [p1Nft.mint] } },
+          { nft: { mints: [p2Nft.mint] } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods.setCollectionAllowlistEnforced(true).rpc();
+
+      // NFTs deposited on acceptance need their metadata and allowlist entry
+      try {
+        await program.methods
+          .acceptChallenge(false)
+          .accountsPartial({ p2: p2.publicKey, challenge: challengePda })
+          .remainingAccounts(
+            nftTransferAccounts([
+              [
+                p2Nft.tokenAccount,
+                escrowAddress(program.programId, challengePda, p2Nft.mint),
+                p2Nft.mint,
+              ],
+            ])
+          )
+          .signers([p2])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "InvalidRemainingAccounts");
+      }
+
+      const { metadata, edition } = tokenMetadataAccounts(p1Nft.mint);
+      try {
+        await program.methods
+          .depositNft()
+          .accountsPartial({
+            depositor: p1.publicKey,
+            challenge: challengePda,
+            fromTokenAccount: p1Nft.tokenAccount,
+            escrowTokenAccount: escrowAddress(program.programId, challengePda, p1Nft.mint),
+            nftMint: p1Nft.mint,
+            nftMetadata: metadata,
+            allowedCollection: null,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been deposited");
+      } catch (error) {
+        assert.include(error.message, "NFT has no verified Metaplex collection");
+      }
+
+      try {
+        await program.methods
+          .lockNft()
+          .accountsPartial({
+            player: p1.publicKey,
+            challenge: challengePda,
+            nftMint: p1Nft.mint,
+            playerTokenAccount: p1Nft.tokenAccount,
+            edition,
+            nftMetadata: null,
+            allowedCollection: null,
+          })
+          .signers([p1])
+          .rpc();
+        assert.fail("NFT should not have been locked");
+      } catch (error) {
+        assert.include(error.message, "Metadata account does not belong to the NFT mint");
+      }
+
+      await program.methods.setCollectionAllowlistEnforced(false).rpc();
+    });
+  });
+
+  describe("valueTier", () => {
+    const tierChallengeId = challengeId + 14;
+    let challengePda: anchor.web3.PublicKey;
+    const p1Stake = { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } };
+    const p2Stake = { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2) } };
+
+    before(() => {
+      challengePda = challengeAddress(program.programId, p1.publicKey, tierChallengeId);
+    });
+
+    const initializeWithRule = (
+      matchRule,
+      signer: Keypair,
+      signedRule = matchRule
+    ) => {
+      const authorization = {
+        expiresAt: new anchor.BN(Math.floor(Date.now() / 1000) + 300),
+        nonce: new anchor.BN(Date.now()),
+      };
+      return program.methods
+        .initializeChallenge(
+          new anchor.BN(tierChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          p1Stake,
+          p2Stake,
+          challengeDeadlines(),
+          matchRule,
+          authorization
+        )
+        .accountsPartial({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+          apiNonce: apiNonceAddress(
+            program.programId,
+            signer.publicKey,
+            authorization.nonce
+          ),
+        })
+        .preInstructions([
+          anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: signer.secretKey,
+            message: priceAttestationMessage(
+              chainId,
+              program.programId,
+              challengePda,
+              p1Stake,
+              p2Stake,
+              signedRule,
+              authorization
+            ),
+          }),
+        ])
+        .signers([p1, apiSigners[0]])
+        .rpc();
+    };
+
+    const valueTier = (p2Tier: number) => ({
+      valueTier: {
+        p1Value: new anchor.BN(100),
+        p2Value: new anchor.BN(90),
+        p1Tier: 2,
+        p2Tier,
+      },
+    });
+
+    it("should reject stakes in different tiers", async () => {
+      try {
+        await initializeWithRule(valueTier(1), apiSigners[0]);
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "Stakes do not satisfy the challenge's matching rule");
+      }
+    });
+
+    it("should reject an attestation for other values", async () => {
+      try {
+        await initializeWithRule(valueTier(2), apiSigners[0], valueTier(3));
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "Expected an Ed25519 signature by an API signer");
+      }
+    });
+
+    it("should reject an attestation not signed by an API signer", async () => {
+      try {
+        await initializeWithRule(valueTier(2), unauthorisedKeypair);
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "UnauthorizedApiSigner");
+      }
+    });
+
+    it("should store an attested value tier", async () => {
+      await initializeWithRule(valueTier(2), apiSigners[0]);
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.matchRule.valueTier.p1Value.toNumber(), 100);
+      assert.equal(challenge.matchRule.valueTier.p2Value.toNumber(), 90);
+      assert.equal(challenge.matchRule.valueTier.p1Tier, 2);
+      assert.equal(challenge.matchRule.valueTier.p2Tier, 2);
+    });
+  });
+
+  describe("initializeAttestedChallenge", () => {
+    const attestedChallengeId = challengeId + 15;
+    const params = {
+      challengeId: new anchor.BN(attestedChallengeId),
+      p1Fee: new anchor.BN(0),
+      p2Fee: new anchor.BN(0),
+      p1Stake: { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } },
+      p2Stake: { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } },
+      deadlines: challengeDeadlines(),
+      matchRule: { any: {} },
+    };
+    const authorization = {
+      expiresAt: new anchor.BN(Math.floor(Date.now() / 1000) + 300),
+      nonce: new anchor.BN(1),
+    };
+    const apiNonce = apiNonceAddress(
+      program.programId,
+      apiSigners[0].publicKey,
+      authorization.nonce
+    );
+    const attestation = (
+      signer: Keypair,
+      signedParams = params,
+      signedAuthorization = authorization
+    ) =>
+      anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+        privateKey: signer.secretKey,
+        message: challengeAttestationDigest(
+          chainId,
+          program.programId,
+          p1.publicKey,
+          p2.publicKey,
+          signedParams,
+          signedAuthorization
+        ),
+      });
+
+    it("should reject an attestation for other terms", async () => {
+      try {
+        await program.methods
+          .initializeAttestedChallenge(params, authorization)
+          .accountsPartial({ p1: p1.publicKey, p2: p2.publicKey, apiNonce })
+          .preInstructions([
+            attestation(apiSigners[0], { ...params, p2Fee: new anchor.BN(1) }),
+          ])
+          .signers([p1])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "Expected an Ed25519 signature by an API signer");
+      }
+    });
+
+    it("should reject a missing attestation", async () => {
+      try {
+        await program.methods
+          .initializeAttestedChallenge(params, authorization)
+          .accountsPartial({ p1: p1.publicKey, p2: p2.publicKey, apiNonce })
+          .signers([p1])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "Expected an Ed25519 signature by an API signer");
+      }
+    });
+
+    it("should create a challenge from an API attestation", async () => {
+      await program.methods
+        .initializeAttestedChallenge(params, authorization)
+        .accountsPartial({ p1: p1.publicKey, p2: p2.publicKey, apiNonce })
+        .preInstructions([attestation(apiSigners[0])])
+        .signers([p1])
+        .rpc();
+
+      const challengePda = challengeAddress(program.programId, p1.publicKey, attestedChallengeId);
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(challenge.p2.toBase58(), p2.publicKey.toBase58());
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ invited: {} }));
+      const usedNonce = await program.account.usedNonce.fetch(apiNonce);
+      assert.equal(usedNonce.expiresAt.toString(), authorization.expiresAt.toString());
+    });
+
+    it("should not accept a nonce twice", async () => {
+      const replayParams = {
+        ...params,
+        challengeId: new anchor.BN(attestedChallengeId + 1),
+      };
+      try {
+        await program.methods
+          .initializeAttestedChallenge(replayParams, authorization)
+          .accountsPartial({ p1: p1.publicKey, p2: p2.publicKey, apiNonce })
+          .preInstructions([attestation(apiSigners[0], replayParams)])
+          .signers([p1])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "API authorization nonce has already been used");
+      }
+    });
+
+    it("should not accept an expired authorization", async () => {
+      const expiredParams = {
+        ...params,
+        challengeId: new anchor.BN(attestedChallengeId + 1),
+      };
+      const expired = {
+        expiresAt: new anchor.BN(Math.floor(Date.now() / 1000) - 60),
+        nonce: new anchor.BN(2),
+      };
+      try {
+        await program.methods
+          .initializeAttestedChallenge(expiredParams, expired)
+          .accountsPartial({
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            apiNonce: apiNonceAddress(
+              program.programId,
+              apiSigners[0].publicKey,
+              expired.nonce
+            ),
+          })
+          .preInstructions([attestation(apiSigners[0], expiredParams, expired)])
+          .signers([p1])
+          .rpc();
+        assert.fail("Challenge should not have initialized");
+      } catch (error) {
+        assert.include(error.message, "API authorization has expired");
+      }
+    });
+
+    it("should not close a nonce before its authorization expires", async () => {
+      try {
+        await program.methods
+          .closeUsedNonce()
+          .accountsPartial({ apiNonce, rentPayer: p1.publicKey })
+          .rpc();
+        assert.fail("Nonce should not have been closed");
+      } catch (error) {
+        assert.include(error.message, "API nonce can't be closed before its authorization expires");
+      }
+    });
+
+    it("should close a used nonce once its authorization expires", async () => {
+      const shortLivedParams = {
+        ...params,
+        challengeId: new anchor.BN(attestedChallengeId + 1),
+      };
+      const shortLived = {
+        expiresAt: new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+        nonce: new anchor.BN(3),
+      };
+      const shortLivedNonce = apiNonceAddress(
+        program.programId,
+        apiSigners[0].publicKey,
+        shortLived.nonce
+      );
+      await program.methods
+        .initializeAttestedChallenge(shortLivedParams, shortLived)
+        .accountsPartial({ p1: p1.publicKey, p2: p2.publicKey, apiNonce: shortLivedNonce })
+        .preInstructions([attestation(apiSigners[0], shortLivedParams, shortLived)])
+        .signers([p1])
+        .rpc();
+      const usedNonce = await program.account.usedNonce.fetch(shortLivedNonce);
+      assert.equal(usedNonce.rentPayer.toBase58(), p1.publicKey.toBase58());
+
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+      const p1BalanceBefore = await provider.connection.getBalance(p1.publicKey);
+      await program.methods
+        .closeUsedNonce()
+        .accountsPartial({ apiNonce: shortLivedNonce, rentPayer: p1.publicKey })
+        .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(shortLivedNonce));
+      assert.isAbove(
+        await provider.connection.getBalance(p1.publicKey),
+        p1BalanceBefore
+      );
+    });
+  });
+
+  describe("openChallenge", () => {
+    const openChallengeId = challengeId + 17;
+    const stake = { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL) } };
+    const openChallengeAddress = (id: number) =>
+      challengeAddress(program.programId, p1.publicKey, id);
+    const initializeOpen = (id: number) =>
+      program.methods
+        .initializeOpenChallenge(
+          new anchor.BN(id),
+          new anchor.BN(0),
+          stake,
+          challengeDeadlines(),
+          { any: {} },
+          {
+            requiredCollection: null,
+            maxFee: new anchor.BN(1000),
+            allowlist: [p2.publicKey],
+          }
+        )
+        .accounts({ p1: p1.publicKey, apiSigner: apiSigners[0].publicKey })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+    const join = (joiner: Keypair, fee: number) =>
+      program.methods
+        .joinChallenge(stake, new anchor.BN(fee))
+        .accounts({
+          p2: joiner.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+          challenge: openChallengeAddress(openChallengeId),
+        })
+        .signers([joiner, apiSigners[0]])
+        .rpc();
+
+    it("should create a challenge without an opponent", async () => {
+      await initializeOpen(openChallengeId);
+
+      const challenge = await program.account.challenge.fetch(
+        openChallengeAddress(openChallengeId)
+      );
+      assert.equal(challenge.p2.toBase58(), anchor.web3.PublicKey.default.toBase58());
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ open: {} }));
+      assert.equal(challenge.joinConstraints.maxFee.toNumber(), 1000);
+    });
+
+    it("should not let a player outside the allowlist join", async () => {
+      try {
+        await join(unauthorisedKeypair, 0);
+        assert.fail("Player should not have joined");
+      } catch (error) {
+        assert.include(error.message, "does not meet the open challenge's join constraints");
+      }
+    });
+
+    it("should not charge the joiner more than the maximum fee", async () => {
+      try {
+        await join(p2, 1001);
+        assert.fail("Player should not have joined");
+      } catch (error) {
+        assert.include(error.message, "above the open challenge's maximum");
+      }
+    });
+
+    it("should give the p2 slot to the first eligible player", async () => {
+      await join(p2, 0);
+
+      const challenge = await program.account.challenge.fetch(
+        openChallengeAddress(openChallengeId)
+      );
+      assert.equal(challenge.p2.toBase58(), p2.publicKey.toBase58());
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ pendingEscrow: {} }));
+      assert.equal(challenge.p2Stake.sol.lamports.toString(), stake.sol.lamports.toString());
+    });
+
+    it("should not let anyone join twice", async () => {
+      try {
+        await join(p2, 0);
+        assert.fail("Player should not have joined");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+    });
+
+    it("should not let moderators resolve an open challenge", async () => {
+      await initializeOpen(openChallengeId + 1);
+      try {
+        await program.methods
+          .resolveChallenge(3) // 1 for p1, 2 for p2, 3 for cancel, 4 for draw
+          .accountsPartial({
+            moderator: moderatorSigners[0].publicKey,
+            challenge: openChallengeAddress(openChallengeId + 1),
+          })
+          .signers([moderatorSigners[0]])
+          .rpc();
+        assert.fail("Open challenge should not have been resolved");
+      } catch (error) {
+        assert.include(error.message, "Invalid challenge state");
+      }
+    });
+
+    it("should let p1 cancel an open challenge nobody joined", async () => {
+      await program.methods
+        .cancelOpenChallenge()
+        .accounts({
+          p1: p1.publicKey,
+          challenge: openChallengeAddress(openChallengeId + 1),
+        })
+        .signers([p1])
+        .rpc();
+
+      const account = await provider.connection.getAccountInfo(
+        openChallengeAddress(openChallengeId + 1)
+      );
+      assert.isNull(account);
+    });
+  });
+
+  describe("stakeChange", () => {
+    const changeChallengeId = challengeId + 19;
+    const p2Stake = { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4) } };
+    let challengePda: anchor.web3.PublicKey;
+    let droppedNft: Awaited<ReturnType<typeof mintNftTo>>;
+    let newNft: Awaited<ReturnType<typeof mintNftTo>>;
+    let change;
+
+    before(async () => {
+      droppedNft = await mintNftTo(provider, p1.publicKey, provider.wallet.payer);
+      newNft = await mintNftTo(provider, p1.publicKey, provider.wallet.payer);
+      challengePda = challengeAddress(program.programId, p1.publicKey, changeChallengeId);
+      change = {
+        p1Fee: new anchor.BN(0),
+        p2Fee: new anchor.BN(0),
+        p1Stake: { nft: { mints: [newNft.mint] } },
+        p2Stake,
+      };
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(changeChallengeId),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          { nft: { mints: [droppedNft.mint] } },
+          p2Stake,
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({ p2: p2.publicKey, challenge: challengePda })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: droppedNft.tokenAccount,
+          escrowTokenAccount: escrowAddress(program.programId, challengePda, droppedNft.mint),
+          nftMint: droppedNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+    });
+
+    it("should let p2 propose a counter-offer", async () => {
+      await program.methods
+        .proposeStakeChange(change)
+        .accountsPartial({
+          proposer: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+          challenge: challengePda,
+        })
+        .signers([p2, apiSigners[0]])
+        .rpc();
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        challenge.proposedChange.proposer.toBase58(),
+        p2.publicKey.toBase58()
+      );
+    });
+
+    it("should not let the proposer accept their own proposal", async () => {
+      try {
+        await program.methods
+          .acceptStakeChange(change)
+          .accountsPartial({
+            accepter: p2.publicKey,
+            p1: p1.publicKey,
+            p2: p2.publicKey,
+            challenge: challengePda,
+          })
+          .signers([p2])
+          .rpc();
+        assert.fail("Stake change should not have been accepted");
+      } catch (error) {
+        assert.include(error.message, "No matching stake change has been proposed");
+      }
+    });
+
+    it("should return NFTs the accepted terms drop", async () => {
+      await program.methods
+        .acceptStakeChange(change)
+        .accountsPartial({
+          accepter: p1.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts(
+          nftTransferAccounts([
+            [
+              escrowAddress(program.programId, challengePda, droppedNft.mint),
+              droppedNft.tokenAccount,
+              droppedNft.mint,
+            ],
+          ])
+        )
+        .signers([p1])
+        .rpc();
+
+      const returned = await getAccount(provider.connection, droppedNft.tokenAccount);
+      assert.equal(returned.amount, BigInt(1));
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          escrowAddress(program.programId, challengePda, droppedNft.mint)
+        )
+      );
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.isNull(challenge.proposedChange);
+      assert.equal(
+        challenge.p1Stake.nft.nfts[0].mint.toBase58(),
+        newNft.mint.toBase58()
+      );
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+
+    it("should return a SOL deposit the accepted terms drop", async () => {
+      const p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+      await program.methods
+        .depositSol()
+        .accountsPartial({ depositor: p2.publicKey, challenge: challengePda })
+        .signers([p2])
+        .rpc();
+
+      const raise = {
+        ...change,
+        p2Stake: { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2) } },
+      };
+      await program.methods
+        .proposeStakeChange(raise)
+        .accountsPartial({
+          proposer: p1.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+          challenge: challengePda,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+
+      const p2BalanceBefore = await provider.connection.getBalance(p2.publicKey);
+      await program.methods
+        .acceptStakeChange(raise)
+        .accountsPartial({
+          accepter: p2.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts([
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p2])
+        .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(p2SolEscrow));
+      // p2 also paid the transaction fee
+      assert.isAbove(
+        await provider.connection.getBalance(p2.publicKey),
+        p2BalanceBefore + p2Stake.sol.lamports.toNumber() - 10_000
+      );
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        challenge.p2Stake.sol.lamports.toString(),
+        raise.p2Stake.sol.lamports.toString()
+      );
+      assert.equal(
+        JSON.stringify(challenge.p2Stake.sol.status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+  });
+
+  describe("withdrawAndMutualCancel", () => {
+    const cancelChallengeId = challengeId + 20;
+    const fee = new anchor.BN(1000);
+    let challengePda: anchor.web3.PublicKey;
+    let escrowNftAccount: anchor.web3.PublicKey;
+    let stakedNft: Awaited<ReturnType<typeof mintNftTo>>;
+
+    before(async () => {
+      stakedNft = await mintNftTo(provider, p1.publicKey, provider.wallet.payer);
+      challengePda = challengeAddress(program.programId, p1.publicKey, cancelChallengeId);
+      escrowNftAccount = escrowAddress(program.programId, challengePda, stakedNft.mint);
+
+      await program.methods
+        .initializeChallenge(
+          new anchor.BN(cancelChallengeId),
+          fee,
+          new anchor.BN(0),
+          { nft: { mints: [stakedNft.mint] } },
+          { sol: { lamports: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 4) } },
+          challengeDeadlines(),
+          { any: {} },
+          null
+        )
+        .accounts({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          apiSigner: apiSigners[0].publicKey,
+        })
+        .signers([p1, apiSigners[0]])
+        .rpc();
+      await program.methods
+        .acceptChallenge(false)
+        .accountsPartial({ p2: p2.publicKey, challenge: challengePda })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .payServiceFee()
+        .accountsPartial({ payer: p1.publicKey, challenge: challengePda })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: stakedNft.tokenAccount,
+          escrowTokenAccount: escrowNftAccount,
+          nftMint: stakedNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+    });
+
+    it("should let p1 withdraw while p2 has deposited nothing", async () => {
+      await program.methods
+        .withdrawDeposit()
+        .accountsPartial({ player: p1.publicKey, challenge: challengePda })
+        .remainingAccounts(
+          nftTransferAccounts([[escrowNftAccount, stakedNft.tokenAccount, stakedNft.mint]])
+        )
+        .signers([p1])
+        .rpc();
+
+      const returned = await getAccount(provider.connection, stakedNft.tokenAccount);
+      assert.equal(returned.amount, BigInt(1));
+      assert.isNull(await provider.connection.getAccountInfo(escrowNftAccount));
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+
+    it("should let p2 withdraw a SOL deposit while p1 has nothing in escrow", async () => {
+      const p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+      await program.methods
+        .depositSol()
+        .accountsPartial({ depositor: p2.publicKey, challenge: challengePda })
+        .signers([p2])
+        .rpc();
+      await program.methods
+        .withdrawDeposit()
+        .accountsPartial({ player: p2.publicKey, challenge: challengePda })
+        .remainingAccounts([
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p2])
+        .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(p2SolEscrow));
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(
+        JSON.stringify(challenge.p2Stake.sol.status),
+        JSON.stringify({ notDeposited: {} })
+      );
+    });
+
+    it("should accept withdrawn stakes again", async () => {
+      await program.methods
+        .depositNft()
+        .accountsPartial({
+          depositor: p1.publicKey,
+          challenge: challengePda,
+          fromTokenAccount: stakedNft.tokenAccount,
+          escrowTokenAccount: escrowNftAccount,
+          nftMint: stakedNft.mint,
+        })
+        .signers([p1])
+        .rpc();
+      await program.methods
+        .depositSol()
+        .accountsPartial({ depositor: p2.publicKey, challenge: challengePda })
+        .signers([p2])
+        .rpc();
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ ready: {} }));
+    });
+
+    it("should cancel and refund fees and stakes when both players sign", async () => {
+      const p2SolEscrow = solEscrowAddress(program.programId, challengePda, p2.publicKey);
+      const p1BalanceBefore = await provider.connection.getBalance(p1.publicKey);
+      const p2BalanceBefore = await provider.connection.getBalance(p2.publicKey);
+      await program.methods
+        .mutualCancel()
+        .accountsPartial({
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+          challenge: challengePda,
+        })
+        .remainingAccounts([
+          ...nftTransferAccounts([[escrowNftAccount, stakedNft.tokenAccount, stakedNft.mint]]),
+          { pubkey: p2SolEscrow, isWritable: true, isSigner: false },
+        ])
+        .signers([p1, p2])
+        .rpc();
+
+      const challenge = await program.account.challenge.fetch(challengePda);
+      assert.equal(JSON.stringify(challenge.status), JSON.stringify({ cancelled: {} }));
+      assert.isTrue(challenge.p1FeeRefunded);
+      assert.equal(
+        JSON.stringify(challenge.p1Stake.nft.nfts[0].status),
+        JSON.stringify({ refunded: {} })
+      );
+      assert.equal(
+        JSON.stringify(challenge.p2Stake.sol.status),
+        JSON.stringify({ refunded: {} })
+      );
+
+      const returned = await getAccount(provider.connection, stakedNft.tokenAccount);
+      assert.equal(returned.amount, BigInt(1));
+      assert.isNull(await provider.connection.getAccountInfo(escrowNftAccount));
+      assert.isNull(await provider.connection.getAccountInfo(p2SolEscrow));
+      // The escrow rent comes back to p1 on top of the fee, less the transaction fee
+      assert.isAbove(
+        await provider.connection.getBalance(p1.publicKey),
+        p1BalanceBefore + fee.toNumber()
+      );
+      assert.isAbove(
+        await provider.connection.getBalance(p2.publicKey),
+        p2BalanceBefore + anchor.web3.LAMPORTS_PER_SOL / 4
+      );
+    });
+
+    it("should close the mutually cancelled challenge", async () => {
+      await program.methods
+        .closeChallenge()
+        .accountsPartial({
+          challenge: challengePda,
+          rentPayer: p1.publicKey,
+          p1: p1.publicKey,
+          p2: p2.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: escrowNftAccount, isWritable: true, isSigner: false },
+          {
+            pubkey: solEscrowAddress(program.programId, challengePda, p2.publicKey),
+            isWritable: true,
+            isSigner: false,
+          },
+        ])
+        .rpc();
+      assert.isNull(await provider.connection.getAccountInfo(challengePda));
+    });
+  });
 });
@@ -1,15 +1,39 @@
 import * as anchor from "@coral-xyz/anchor";
+import { keccak_256, sha3_256 } from "@noble/hashes/sha3";
+import {
+  createCreateInstruction,
+  createMintInstruction,
+  PROGRAM_ID as TOKEN_METADATA_PROGRAM_ID,
+  TokenStandard,
+} from "@metaplex-foundation/mpl-token-metadata";
 // import {
 //   createCreateMetadataAccountV3Instruction,
 //   DataV2,
 //   PROGRAM_ID as TOKEN_METADATA_PROGRAM_ID,
 // } from "@metaplex-foundation/mpl-token-metadata";
 import {
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  createInitializeMintInstruction,
+  createInitializeNonTransferableMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
   createMint,
+  ExtensionType,
+  getAssociatedTokenAddressSync,
+  getMintLen,
   getOrCreateAssociatedTokenAccount,
   mintTo,
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
-import { Keypair, PublicKey } from "@solana/web3.js";
+import {
+  Keypair,
+  PublicKey,
+  sendAndConfirmTransaction,
+  SystemProgram,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  Transaction,
+  TransactionInstruction,
+} from "@solana/web3.js";
 
 /**
  * Mints a 0-decimal token (NFT) to a recipient and sets metadata using Metaplex v2.
@@ -114,3 +138,780 @@ export async function mintNftTo(
   //     tokenAccount: tokenAccount.address,
   //   };
 }
+
+/**
+ * Derives the program's config PDA.
+ */
+export function configAddress(programId: PublicKey): PublicKey {
+  return PublicKey.findProgramAddressSync([Buffer.from("config")], programId)[0];
+}
+
+/**
+ * Derives the PDA of p1's challenge with the given id.
+ */
+export function challengeAddress(
+  programId: PublicKey,
+  p1: PublicKey,
+  challengeId: number | anchor.BN
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("challenge"),
+      p1.toBuffer(),
+      new anchor.BN(challengeId).toArrayLike(Buffer, "le", 8),
+    ],
+    programId
+  )[0];
+}
+
+/**
+ * Derives the escrow token account PDA holding a staked NFT.
+ */
+export function escrowAddress(
+  programId: PublicKey,
+  challenge: PublicKey,
+  mint: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("escrow"), challenge.toBuffer(), mint.toBuffer()],
+    programId
+  )[0];
+}
+
+/**
+ * Derives the escrow token account PDA holding a player's token stake.
+ */
+export function tokenEscrowAddress(
+  programId: PublicKey,
+  challenge: PublicKey,
+  player: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("token_escrow"), challenge.toBuffer(), player.toBuffer()],
+    programId
+  )[0];
+}
+
+/**
+ * Derives the `SolEscrow` PDA holding a player's SOL stake.
+ */
+export function solEscrowAddress(
+  programId: PublicKey,
+  challenge: PublicKey,
+  player: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("sol_escrow"), challenge.toBuffer(), player.toBuffer()],
+    programId
+  )[0];
+}
+
+/**
+ * Derives the allowlist entry PDA of a collection.
+ */
+export function allowedCollectionAddress(
+  programId: PublicKey,
+  collection: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("allowed_collection"), collection.toBuffer()],
+    programId
+  )[0];
+}
+
+/**
+ * Mints a Token-2022 NFT to a recipient, optionally non-transferable.
+ */
+export async function mintToken2022NftTo(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  payer: Keypair,
+  nonTransferable = false
+): Promise<{ mint: PublicKey; tokenAccount: PublicKey }> {
+  const connection = provider.connection;
+  const mint = Keypair.generate();
+  const extensions = nonTransferable ? [ExtensionType.NonTransferable] : [];
+  const mintLen = getMintLen(extensions);
+
+  const tx = new Transaction().add(
+    SystemProgram.createAccount({
+      fromPubkey: payer.publicKey,
+      newAccountPubkey: mint.publicKey,
+      space: mintLen,
+      lamports: await connection.getMinimumBalanceForRentExemption(mintLen),
+      programId: TOKEN_2022_PROGRAM_ID,
+    })
+  );
+  if (nonTransferable) {
+    tx.add(
+      createInitializeNonTransferableMintInstruction(
+        mint.publicKey,
+        TOKEN_2022_PROGRAM_ID
+      )
+    );
+  }
+  tx.add(
+    createInitializeMintInstruction(
+      mint.publicKey,
+      0,
+      payer.publicKey,
+      null,
+      TOKEN_2022_PROGRAM_ID
+    )
+  );
+  await sendAndConfirmTransaction(connection, tx, [payer, mint]);
+
+  const ata = await getOrCreateAssociatedTokenAccount(
+    connection,
+    payer,
+    mint.publicKey,
+    recipient,
+    false,
+    undefined,
+    undefined,
+    TOKEN_2022_PROGRAM_ID
+  );
+  await mintTo(
+    connection,
+    payer,
+    mint.publicKey,
+    ata.address,
+    payer,
+    1,
+    [],
+    undefined,
+    TOKEN_2022_PROGRAM_ID
+  );
+
+  return {
+    mint: mint.publicKey,
+    tokenAccount: ata.address,
+  };
+}
+
+/**
+ * Creates a 0 decimal Token-2022 mint that withholds `feeBasisPoints` of
+ * every transfer, and mints `amount` of it to the recipient's associated
+ * token account.
+ */
+export async function mintTransferFeeTokensTo(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  payer: Keypair,
+  feeBasisPoints: number,
+  amount: number
+): Promise<{ mint: PublicKey; tokenAccount: PublicKey }> {
+  const connection = provider.connection;
+  const mint = Keypair.generate();
+  const mintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+
+  const tx = new Transaction().add(
+    SystemProgram.createAccount({
+      fromPubkey: payer.publicKey,
+      newAccountPubkey: mint.publicKey,
+      space: mintLen,
+      lamports: await connection.getMinimumBalanceForRentExemption(mintLen),
+      programId: TOKEN_2022_PROGRAM_ID,
+    }),
+    createInitializeTransferFeeConfigInstruction(
+      mint.publicKey,
+      payer.publicKey,
+      payer.publicKey,
+      feeBasisPoints,
+      BigInt(amount),
+      TOKEN_2022_PROGRAM_ID
+    ),
+    createInitializeMintInstruction(
+      mint.publicKey,
+      0,
+      payer.publicKey,
+      null,
+      TOKEN_2022_PROGRAM_ID
+    )
+  );
+  await sendAndConfirmTransaction(connection, tx, [payer, mint]);
+
+  const ata = await getOrCreateAssociatedTokenAccount(
+    connection,
+    payer,
+    mint.publicKey,
+    recipient,
+    false,
+    undefined,
+    undefined,
+    TOKEN_2022_PROGRAM_ID
+  );
+  await mintTo(
+    connection,
+    payer,
+    mint.publicKey,
+    ata.address,
+    payer,
+    amount,
+    [],
+    undefined,
+    TOKEN_2022_PROGRAM_ID
+  );
+
+  return {
+    mint: mint.publicKey,
+    tokenAccount: ata.address,
+  };
+}
+
+/**
+ * Derives the Token Metadata accounts for a mint, plus the token record of
+ * each given token account.
+ */
+export function tokenMetadataAccounts(
+  mint: PublicKey,
+  tokenAccounts: PublicKey[] = []
+): { metadata: PublicKey; edition: PublicKey; tokenRecords: PublicKey[] } {
+  const seeds = [
+    Buffer.from("metadata"),
+    TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+    mint.toBuffer(),
+  ];
+  const pda = (extra: Buffer[]) =>
+    PublicKey.findProgramAddressSync(
+      [...seeds, ...extra],
+      TOKEN_METADATA_PROGRAM_ID
+    )[0];
+
+  return {
+    metadata: pda([]),
+    edition: pda([Buffer.from("edition")]),
+    tokenRecords: tokenAccounts.map((tokenAccount) =>
+      pda([Buffer.from("token_record"), tokenAccount.toBuffer()])
+    ),
+  };
+}
+
+/**
+ * Mints a Metaplex NFT with a master edition to a recipient, optionally as a
+ * programmable NFT (pNFT) without a rule set.
+ */
+export async function mintMetaplexNftTo(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  payer: Keypair,
+  programmable = false
+): Promise<{ mint: PublicKey; tokenAccount: PublicKey }> {
+  const mint = Keypair.generate();
+  const tokenAccount = getAssociatedTokenAddressSync(mint.publicKey, recipient);
+  const {
+    metadata,
+    edition,
+    tokenRecords: [tokenRecord],
+  } = tokenMetadataAccounts(mint.publicKey, [tokenAccount]);
+
+  const createIx = createCreateInstruction(
+    {
+      metadata,
+      masterEdition: edition,
+      mint: mint.publicKey,
+      authority: payer.publicKey,
+      payer: payer.publicKey,
+      updateAuthority: payer.publicKey,
+      sysvarInstructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      splTokenProgram: TOKEN_PROGRAM_ID,
+    },
+    {
+      createArgs: {
+        __kind: "V1",
+        assetData: {
+          name: programmable ? "Test pNFT" : "Test NFT",
+          symbol: "TEST",
+          uri: "https://example.com/metadata.json",
+          sellerFeeBasisPoints: 0,
+          creators: null,
+          primarySaleHappened: false,
+          isMutable: true,
+          tokenStandard: programmable
+            ? TokenStandard.ProgrammableNonFungible
+            : TokenStandard.NonFungible,
+          collection: null,
+          uses: null,
+          collectionDetails: null,
+          ruleSet: null,
+        },
+        decimals: 0,
+        printSupply: { __kind: "Zero" },
+      },
+    }
+  );
+  // The mint is created by Token Metadata, so it has to sign
+  createIx.keys.find((key) => key.pubkey.equals(mint.publicKey)).isSigner =
+    true;
+
+  const mintIx = createMintInstruction(
+    {
+      token: tokenAccount,
+      tokenOwner: recipient,
+      metadata,
+      masterEdition: edition,
+      tokenRecord: programmable ? tokenRecord : undefined,
+      mint: mint.publicKey,
+      authority: payer.publicKey,
+      payer: payer.publicKey,
+      sysvarInstructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      splTokenProgram: TOKEN_PROGRAM_ID,
+      splAtaProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+    },
+    { mintArgs: { __kind: "V1", amount: 1, authorizationData: null } }
+  );
+
+  await sendAndConfirmTransaction(
+    provider.connection,
+    new Transaction().add(createIx, mintIx),
+    [payer, mint]
+  );
+
+  return { mint: mint.publicKey, tokenAccount };
+}
+
+export const BUBBLEGUM_PROGRAM_ID = new PublicKey(
+  "BGUMAp9Gq7iTEuizy4pqaxsTyUCBK68MDfK752saRPUY"
+);
+export const SPL_ACCOUNT_COMPRESSION_PROGRAM_ID = new PublicKey(
+  "cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK"
+);
+export const SPL_NOOP_PROGRAM_ID = new PublicKey(
+  "noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV"
+);
+
+// Smallest tree Account Compression accepts, no canopy
+const CNFT_TREE_DEPTH = 3;
+const CNFT_TREE_BUFFER = 8;
+// Account type, version and the V1 header
+const CNFT_TREE_HEADER = 56;
+// sequence_number, active_index and buffer_size before the change logs
+const CNFT_TREE_COUNTERS = 24;
+const CNFT_CHANGE_LOG = 32 + 32 * CNFT_TREE_DEPTH + 8;
+const CNFT_TREE_SIZE =
+  CNFT_TREE_HEADER +
+  CNFT_TREE_COUNTERS +
+  CNFT_TREE_BUFFER * CNFT_CHANGE_LOG +
+  (32 * CNFT_TREE_DEPTH + 40);
+
+/**
+ * A compressed NFT minted alone into its own Merkle tree by `mintCnftTo`.
+ */
+export type Cnft = {
+  merkleTree: PublicKey;
+  treeConfig: PublicKey;
+  assetId: PublicKey;
+  dataHash: number[];
+  creatorHash: number[];
+};
+
+const keccak = (...parts: Uint8Array[]) =>
+  Buffer.from(keccak_256(Buffer.concat(parts)));
+
+const borshString = (value: string) => {
+  const len = Buffer.alloc(4);
+  len.writeUInt32LE(Buffer.byteLength(value));
+  return Buffer.concat([len, Buffer.from(value)]);
+};
+
+/**
+ * Creates a Bubblegum tree and mints a single cNFT into it, owned and
+ * delegated to the recipient.
+ */
+export async function mintCnftTo(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  payer: Keypair
+): Promise<Cnft> {
+  const connection = provider.connection;
+  const merkleTree = Keypair.generate();
+  const [treeConfig] = PublicKey.findProgramAddressSync(
+    [merkleTree.publicKey.toBuffer()],
+    BUBBLEGUM_PROGRAM_ID
+  );
+  const [assetId] = PublicKey.findProgramAddressSync(
+    [Buffer.from("asset"), merkleTree.publicKey.toBuffer(), u64(0)],
+    BUBBLEGUM_PROGRAM_ID
+  );
+
+  const treeArgs = Buffer.alloc(9);
+  treeArgs.writeUInt32LE(CNFT_TREE_DEPTH, 0);
+  treeArgs.writeUInt32LE(CNFT_TREE_BUFFER, 4);
+  // public: None
+  const createTreeIx = new TransactionInstruction({
+    programId: BUBBLEGUM_PROGRAM_ID,
+    keys: [
+      { pubkey: treeConfig, isWritable: true, isSigner: false },
+      { pubkey: merkleTree.publicKey, isWritable: true, isSigner: false },
+      { pubkey: payer.publicKey, isWritable: true, isSigner: true },
+      { pubkey: payer.publicKey, isWritable: false, isSigner: true },
+      { pubkey: SPL_NOOP_PROGRAM_ID, isWritable: false, isSigner: false },
+      {
+        pubkey: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        isWritable: false,
+        isSigner: false,
+      },
+      { pubkey: SystemProgram.programId, isWritable: false, isSigner: false },
+    ],
+    data: Buffer.concat([
+      Buffer.from([165, 83, 136, 142, 89, 202, 47, 220]),
+      treeArgs,
+    ]),
+  });
+
+  const metadataArgs = Buffer.concat([
+    borshString("Test cNFT"),
+    borshString("TEST"),
+    borshString("https://example.com/metadata.json"),
+    Buffer.from([0, 0]), // seller_fee_basis_points
+    Buffer.from([0, 1]), // primary_sale_happened, is_mutable
+    Buffer.from([0]), // edition_nonce: None
+    Buffer.from([1, 0]), // token_standard: Some(NonFungible)
+    Buffer.from([0, 0]), // collection, uses: None
+    Buffer.from([0]), // token_program_version: Original
+    Buffer.from([0, 0, 0, 0]), // creators: []
+  ]);
+  const mintIx = new TransactionInstruction({
+    programId: BUBBLEGUM_PROGRAM_ID,
+    keys: [
+      { pubkey: treeConfig, isWritable: true, isSigner: false },
+      { pubkey: recipient, isWritable: false, isSigner: false },
+      { pubkey: recipient, isWritable: false, isSigner: false },
+      { pubkey: merkleTree.publicKey, isWritable: true, isSigner: false },
+      { pubkey: payer.publicKey, isWritable: true, isSigner: true },
+      { pubkey: payer.publicKey, isWritable: false, isSigner: true },
+      { pubkey: SPL_NOOP_PROGRAM_ID, isWritable: false, isSigner: false },
+      {
+        pubkey: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        isWritable: false,
+        isSigner: false,
+      },
+      { pubkey: SystemProgram.programId, isWritable: false, isSigner: false },
+    ],
+    data: Buffer.concat([
+      Buffer.from([145, 98, 192, 118, 184, 147, 118, 104]),
+      metadataArgs,
+    ]),
+  });
+
+  await sendAndConfirmTransaction(
+    connection,
+    new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: payer.publicKey,
+        newAccountPubkey: merkleTree.publicKey,
+        space: CNFT_TREE_SIZE,
+        lamports: await connection.getMinimumBalanceForRentExemption(
+          CNFT_TREE_SIZE
+        ),
+        programId: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      }),
+      createTreeIx,
+      mintIx
+    ),
+    [payer, merkleTree]
+  );
+
+  // Seller fee basis points are hashed again after the metadata
+  const dataHash = keccak(keccak(metadataArgs), Buffer.alloc(2));
+
+  return {
+    merkleTree: merkleTree.publicKey,
+    treeConfig,
+    assetId,
+    dataHash: [...dataHash],
+    // Hash of an empty creator list
+    creatorHash: [...keccak()],
+  };
+}
+
+/**
+ * Nodes of the empty subtrees next to leaf 0, which is the whole Merkle
+ * proof of a tree holding a single cNFT.
+ */
+function emptyCnftProof(): Buffer[] {
+  const proof = [Buffer.alloc(32)];
+  while (proof.length < CNFT_TREE_DEPTH) {
+    const node = proof[proof.length - 1];
+    proof.push(keccak(node, node));
+  }
+  return proof;
+}
+
+/**
+ * Reads the current root of a cNFT's tree and returns the `CnftLeaf`
+ * argument and Merkle proof accounts for a Bubblegum transfer.
+ */
+export async function cnftTransferArgs(
+  provider: anchor.AnchorProvider,
+  cnft: Cnft
+): Promise<{ leaf: any; proof: anchor.web3.AccountMeta[] }> {
+  const tree = await provider.connection.getAccountInfo(cnft.merkleTree);
+  const activeIndex = Number(tree.data.readBigUInt64LE(CNFT_TREE_HEADER + 8));
+  const rootOffset =
+    CNFT_TREE_HEADER + CNFT_TREE_COUNTERS + activeIndex * CNFT_CHANGE_LOG;
+
+  return {
+    leaf: {
+      root: [...tree.data.subarray(rootOffset, rootOffset + 32)],
+      dataHash: cnft.dataHash,
+      creatorHash: cnft.creatorHash,
+      nonce: new anchor.BN(0),
+      index: 0,
+    },
+    proof: emptyCnftProof().map((node) => ({
+      pubkey: new PublicKey(node),
+      isWritable: false,
+      isSigner: false,
+    })),
+  };
+}
+
+/**
+ * Computes the root a cNFT's tree has while the cNFT is owned and
+ * delegated to `owner`.
+ */
+export function cnftRoot(cnft: Cnft, owner: PublicKey): number[] {
+  const leaf = keccak(
+    Buffer.from([1]), // LeafSchema V1
+    cnft.assetId.toBuffer(),
+    owner.toBuffer(),
+    owner.toBuffer(),
+    u64(0),
+    Buffer.from(cnft.dataHash),
+    Buffer.from(cnft.creatorHash)
+  );
+  return [
+    ...emptyCnftProof().reduce((node, sibling) => keccak(node, sibling), leaf),
+  ];
+}
+
+export const MPL_CORE_PROGRAM_ID = new PublicKey(
+  "CoREENxT6tW1HoK8ypY1SxRMZTcVPm7R94rH4PZNhX7d"
+);
+
+/**
+ * Creates a Metaplex Core asset without a collection or plugins, owned by
+ * the recipient.
+ */
+export async function mintCoreAssetTo(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  payer: Keypair
+): Promise<PublicKey> {
+  const asset = Keypair.generate();
+  // Unused optional accounts are passed as the mpl-core program id
+  const none = {
+    pubkey: MPL_CORE_PROGRAM_ID,
+    isWritable: false,
+    isSigner: false,
+  };
+  const createIx = new TransactionInstruction({
+    programId: MPL_CORE_PROGRAM_ID,
+    keys: [
+      { pubkey: asset.publicKey, isWritable: true, isSigner: true },
+      none, // collection
+      none, // authority, defaults to the payer
+      { pubkey: payer.publicKey, isWritable: true, isSigner: true },
+      { pubkey: recipient, isWritable: false, isSigner: false },
+      none, // update authority, defaults to the authority
+      { pubkey: SystemProgram.programId, isWritable: false, isSigner: false },
+      none, // log wrapper
+    ],
+    data: Buffer.concat([
+      Buffer.from([0]), // CreateV1
+      Buffer.from([0]), // data_state: AccountState
+      borshString("Test Core asset"),
+      borshString("https://example.com/metadata.json"),
+      Buffer.from([0]), // plugins: None
+    ]),
+  });
+
+  await sendAndConfirmTransaction(
+    provider.connection,
+    new Transaction().add(createIx),
+    [payer, asset]
+  );
+
+  return asset.publicKey;
+}
+
+/**
+ * Reads the owner of a Core asset, stored right after its key byte.
+ */
+export async function coreAssetOwner(
+  provider: anchor.AnchorProvider,
+  asset: PublicKey
+): Promise<PublicKey> {
+  const account = await provider.connection.getAccountInfo(asset);
+  return new PublicKey(account.data.subarray(1, 33));
+}
+
+/**
+ * Builds challenge deadlines relative to the current time.
+ * Each stage gets `stageSeconds` after the previous one.
+ */
+export function challengeDeadlines(stageSeconds = 3600): {
+  fee: anchor.BN;
+  escrow: anchor.BN;
+  resolution: anchor.BN;
+} {
+  const now = Math.floor(Date.now() / 1000);
+  return {
+    fee: new anchor.BN(now + stageSeconds),
+    escrow: new anchor.BN(now + stageSeconds * 2),
+    resolution: new anchor.BN(now + stageSeconds * 3),
+  };
+}
+
+/**
+ * Flattens (from, to, mint) triples into the remaining accounts the program
+ * expects for bundle deposits, claims and refunds.
+ */
+export function nftTransferAccounts(
+  triples: [PublicKey, PublicKey, PublicKey][]
+): anchor.web3.AccountMeta[] {
+  return triples.flatMap(([from, to, mint]) => [
+    { pubkey: from, isWritable: true, isSigner: false },
+    { pubkey: to, isWritable: true, isSigner: false },
+    { pubkey: mint, isWritable: false, isSigner: false },
+  ]);
+}
+
+const u64 = (value: anchor.BN | number) =>
+  new anchor.BN(value).toArrayLike(Buffer, "le", 8);
+
+/**
+ * Borsh-encodes `StakeTerms` the way the program serializes them.
+ */
+export function encodeStakeTerms(terms: any): Buffer {
+  if (terms.nft) {
+    const len = Buffer.alloc(4);
+    len.writeUInt32LE(terms.nft.mints.length);
+    return Buffer.concat([
+      Buffer.from([0]),
+      len,
+      ...terms.nft.mints.map((mint: PublicKey) => mint.toBuffer()),
+    ]);
+  }
+  if (terms.token) {
+    return Buffer.concat([
+      Buffer.from([1]),
+      terms.token.mint.toBuffer(),
+      u64(terms.token.amount),
+    ]);
+  }
+  if (terms.sol) {
+    return Buffer.concat([Buffer.from([2]), u64(terms.sol.lamports)]);
+  }
+  if (terms.cnft) {
+    return Buffer.concat([
+      Buffer.from([3]),
+      terms.cnft.merkleTree.toBuffer(),
+      terms.cnft.assetId.toBuffer(),
+    ]);
+  }
+  return Buffer.concat([Buffer.from([4]), terms.core.asset.toBuffer()]);
+}
+
+/**
+ * Borsh-encodes a `MatchRule` the way the program serializes it.
+ */
+export function encodeMatchRule(rule: any): Buffer {
+  if (rule.valueTier) {
+    return Buffer.concat([
+      Buffer.from([2]),
+      u64(rule.valueTier.p1Value),
+      u64(rule.valueTier.p2Value),
+      Buffer.from([rule.valueTier.p1Tier, rule.valueTier.p2Tier]),
+    ]);
+  }
+  return Buffer.from([rule.sameCollection ? 1 : 0]);
+}
+
+/**
+ * Builds the message the API signs to attest the value tier of both stakes.
+ */
+export function priceAttestationMessage(
+  chainId: number[],
+  programId: PublicKey,
+  challenge: PublicKey,
+  p1Stake: any,
+  p2Stake: any,
+  matchRule: any,
+  authorization: { expiresAt: anchor.BN; nonce: anchor.BN }
+): Buffer {
+  return Buffer.concat([
+    Buffer.from("trinity:price_attestation"),
+    Buffer.from(chainId),
+    programId.toBuffer(),
+    challenge.toBuffer(),
+    encodeStakeTerms(p1Stake),
+    encodeStakeTerms(p2Stake),
+    encodeMatchRule(matchRule),
+    encodeApiAuthorization(authorization),
+  ]);
+}
+
+/**
+ * Borsh-encodes an `ApiAuthorization`.
+ */
+export function encodeApiAuthorization(authorization: {
+  expiresAt: anchor.BN;
+  nonce: anchor.BN;
+}): Buffer {
+  return Buffer.concat([
+    authorization.expiresAt.toTwos(64).toArrayLike(Buffer, "le", 8),
+    u64(authorization.nonce),
+  ]);
+}
+
+/**
+ * Derives the PDA that records an API signer's nonce as used.
+ */
+export function apiNonceAddress(
+  programId: PublicKey,
+  signer: PublicKey,
+  nonce: anchor.BN
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("api_nonce"), signer.toBuffer(), u64(nonce)],
+    programId
+  )[0];
+}
+
+/**
+ * Builds the digest the API signs to authorise a challenge off-chain,
+ * for `initializeAttestedChallenge`.
+ */
+export function challengeAttestationDigest(
+  chainId: number[],
+  programId: PublicKey,
+  p1: PublicKey,
+  p2: PublicKey,
+  params: any,
+  authorization: { expiresAt: anchor.BN; nonce: anchor.BN }
+): Buffer {
+  const i64 = (value: anchor.BN) => value.toTwos(64).toArrayLike(Buffer, "le", 8);
+  return Buffer.from(
+    sha3_256(
+      Buffer.concat([
+        Buffer.from("trinity:challenge_attestation"),
+        Buffer.from(chainId),
+        programId.toBuffer(),
+        p1.toBuffer(),
+        p2.toBuffer(),
+        u64(params.challengeId),
+        u64(params.p1Fee),
+        u64(params.p2Fee),
+        encodeStakeTerms(params.p1Stake),
+        encodeStakeTerms(params.p2Stake),
+        i64(params.deadlines.fee),
+        i64(params.deadlines.escrow),
+        i64(params.deadlines.resolution),
+        encodeMatchRule(params.matchRule),
+        encodeApiAuthorization(authorization),
+      ])
+    )
+  );
+}